@@ -0,0 +1,707 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time;
+
+use crate::auth::{AdbKeyStore, AUTH_RSAPUBLICKEY, AUTH_SIGNATURE, AUTH_TOKEN};
+use crate::error::AdbError;
+use crate::proto::FeatureSet;
+use crate::tls;
+
+// https://android.googlesource.com/platform/packages/modules/adb/+/refs/heads/main/protocol.txt
+pub const A_SYNC: u32 = 0x434e5953;
+pub const A_CNXN: u32 = 0x4e584e43;
+pub const A_OPEN: u32 = 0x4e45504f;
+pub const A_OKAY: u32 = 0x59414b4f;
+pub const A_CLSE: u32 = 0x45534c43;
+pub const A_WRTE: u32 = 0x45545257;
+pub const A_AUTH: u32 = 0x48545541;
+pub const A_STLS: u32 = 0x534c5453;
+
+pub const A_VERSION: u32 = 0x01000001;
+pub const A_STLS_VERSION: u32 = 0x01000000;
+pub const MAX_PAYLOAD: u32 = 256 * 1024;
+
+const HEADER_SIZE: usize = 24;
+const HOST_BANNER: &[u8] = b"host::\0";
+
+/// One message of the device-side protocol, a 24 byte header followed by the payload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub command: u32,
+    pub arg0: u32,
+    pub arg1: u32,
+    pub data: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(command: u32, arg0: u32, arg1: u32, data: &[u8]) -> Message {
+        Message {
+            command,
+            arg0,
+            arg1,
+            data: data.to_vec(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_SIZE + self.data.len());
+        for field in [
+            self.command,
+            self.arg0,
+            self.arg1,
+            self.data.len() as u32,
+            checksum(&self.data),
+            self.command ^ 0xffffffff,
+        ] {
+            packet.extend_from_slice(&field.to_le_bytes());
+        }
+        packet.extend_from_slice(&self.data);
+        packet
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<(), AdbError> {
+        // a TLS connection buffers writes until flushed
+        writer
+            .write_all(&self.encode())
+            .and_then(|_| writer.flush())
+            .map_err(AdbError::from_write)
+    }
+
+    /// Read one message, the checksum is only verified when the sender filled it in.
+    pub fn read_from(reader: &mut impl Read) -> Result<Message, AdbError> {
+        let mut header = [0; HEADER_SIZE];
+        reader
+            .read_exact(&mut header)
+            .map_err(AdbError::from_read)?;
+        let field = |i: usize| {
+            u32::from_le_bytes([
+                header[i * 4],
+                header[i * 4 + 1],
+                header[i * 4 + 2],
+                header[i * 4 + 3],
+            ])
+        };
+        let (command, length, crc, magic) = (field(0), field(3), field(4), field(5));
+        if magic != command ^ 0xffffffff {
+            return Err(AdbError::ResponseStatusError {
+                content: format!("invalid message magic: {:#x}", magic),
+            });
+        }
+        if length > MAX_PAYLOAD * 4 {
+            return Err(AdbError::ResponseStatusError {
+                content: format!("message payload too large: {}", length),
+            });
+        }
+        let mut data = vec![0; length as usize];
+        reader.read_exact(&mut data).map_err(AdbError::from_read)?;
+        if crc != 0 && crc != checksum(&data) {
+            return Err(AdbError::ResponseStatusError {
+                content: String::from("message checksum mismatch"),
+            });
+        }
+        Ok(Message {
+            command,
+            arg0: field(1),
+            arg1: field(2),
+            data,
+        })
+    }
+}
+
+/// Sum of the payload bytes, protocol versions before 0x01000001 require it.
+pub fn checksum(data: &[u8]) -> u32 {
+    data.iter()
+        .fold(0u32, |sum, byte| sum.wrapping_add(*byte as u32))
+}
+
+#[derive(Debug, Default)]
+struct StreamState {
+    remote_id: u32,
+    buffer: VecDeque<u8>,
+    // set by the device's OKAY, a stream may only have one WRTE in flight
+    ready: bool,
+    // the device's WRTE in `buffer` is only acknowledged once it was read, so the device
+    // can't send more than the reader keeps up with
+    unacked: bool,
+    closed: bool,
+}
+
+// plain TCP, or TLS once adbd asked for it with STLS
+trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+struct Inner {
+    conn: Box<dyn Connection>,
+    max_payload: usize,
+    next_id: u32,
+    streams: HashMap<u32, StreamState>,
+}
+
+impl std::fmt::Debug for Inner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Inner")
+            .field("max_payload", &self.max_payload)
+            .field("next_id", &self.next_id)
+            .field("streams", &self.streams)
+            .finish()
+    }
+}
+
+impl Inner {
+    fn send(&mut self, message: Message) -> Result<(), AdbError> {
+        message.write_to(&mut self.conn)
+    }
+
+    // read one message and hand it to the stream it belongs to
+    fn pump(&mut self) -> Result<(), AdbError> {
+        let message = Message::read_from(&mut self.conn)?;
+        let (remote_id, local_id) = (message.arg0, message.arg1);
+        match message.command {
+            A_WRTE => match self.streams.get_mut(&local_id) {
+                Some(stream) if !stream.closed => {
+                    if message.data.is_empty() {
+                        self.send(Message::new(A_OKAY, local_id, remote_id, &[]))?;
+                    } else {
+                        stream.buffer.extend(message.data);
+                        stream.unacked = true;
+                    }
+                }
+                _ => self.send(Message::new(A_CLSE, local_id, remote_id, &[]))?,
+            },
+            A_OKAY => {
+                if let Some(stream) = self.streams.get_mut(&local_id) {
+                    stream.remote_id = remote_id;
+                    stream.ready = true;
+                }
+            }
+            A_CLSE => {
+                if let Some(stream) = self.streams.get_mut(&local_id) {
+                    stream.closed = true;
+                }
+            }
+            command => log::debug!("ignore message {:#x}", command),
+        }
+        Ok(())
+    }
+}
+
+/// A connection straight to adbd over TCP, no adb server involved. Streams opened on it are
+/// multiplexed over the one socket; a blocked read holds the socket for the other streams.
+#[derive(Debug, Clone)]
+pub struct AdbdClient {
+    inner: Arc<Mutex<Inner>>,
+    banner: String,
+}
+
+impl AdbdClient {
+    /// Connect to `addr`, e.g. "192.168.1.20:5555", and authenticate with `keys`. A zero
+    /// `time_out` blocks forever.
+    pub fn connect(
+        addr: &str,
+        time_out: time::Duration,
+        keys: &AdbKeyStore,
+    ) -> Result<AdbdClient, AdbError> {
+        let conn = TcpStream::connect(addr).map_err(|error| AdbError::TcpConnectError {
+            source: Box::new(error),
+        })?;
+        if !time_out.is_zero() {
+            for result in [
+                conn.set_read_timeout(Some(time_out)),
+                conn.set_write_timeout(Some(time_out)),
+            ] {
+                result.map_err(|error| AdbError::TcpConnectError {
+                    source: Box::new(error),
+                })?;
+            }
+        }
+        AdbdClient::handshake(conn, keys)
+    }
+
+    /// Exchange CNXN messages on an already connected socket. An AUTH token is answered with
+    /// a signature from every key in turn, when adbd accepts none of them the first public
+    /// key is sent so the user can confirm it on the device. A device paired over wireless
+    /// debugging answers with STLS instead, the socket is then upgraded to TLS with the first key.
+    pub fn handshake(conn: TcpStream, keys: &AdbKeyStore) -> Result<AdbdClient, AdbError> {
+        let host = conn
+            .peer_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_default();
+        let mut conn: Box<dyn Connection> = Box::new(conn);
+        Message::new(A_CNXN, A_VERSION, MAX_PAYLOAD, HOST_BANNER).write_to(&mut conn)?;
+        let mut signing_keys = keys.keys().iter();
+        let mut sent_public_key = false;
+        loop {
+            let reply = match Message::read_from(&mut conn) {
+                Ok(reply) => reply,
+                // nobody confirmed the authorization dialog in time
+                Err(AdbError::Timeout { .. }) if sent_public_key => {
+                    return Err(AdbError::Unauthorized)
+                }
+                Err(error) => return Err(error),
+            };
+            match reply.command {
+                A_CNXN => return Ok(AdbdClient::from_connection(conn, &reply)),
+                A_STLS => {
+                    let key = keys.keys().first().ok_or(AdbError::Unauthorized)?;
+                    Message::new(A_STLS, A_STLS_VERSION, 0, &[]).write_to(&mut conn)?;
+                    conn = Box::new(tls::connect(key, &host, conn)?);
+                }
+                A_AUTH if reply.arg0 == AUTH_TOKEN => {
+                    if let Some(key) = signing_keys.next() {
+                        let signature = key.sign_token(&reply.data)?;
+                        Message::new(A_AUTH, AUTH_SIGNATURE, 0, &signature).write_to(&mut conn)?;
+                    } else if let (Some(key), false) = (keys.keys().first(), sent_public_key) {
+                        let mut public_key = key.public_key_string().into_bytes();
+                        public_key.push(0);
+                        Message::new(A_AUTH, AUTH_RSAPUBLICKEY, 0, &public_key)
+                            .write_to(&mut conn)?;
+                        sent_public_key = true;
+                    } else {
+                        return Err(AdbError::Unauthorized);
+                    }
+                }
+                command => {
+                    return Err(AdbError::ResponseStatusError {
+                        content: format!("unexpected reply to CNXN: {:#x}", command),
+                    })
+                }
+            }
+        }
+    }
+
+    fn from_connection(conn: Box<dyn Connection>, cnxn: &Message) -> AdbdClient {
+        let max_payload = cnxn.arg1.clamp(1, MAX_PAYLOAD) as usize;
+        AdbdClient {
+            inner: Arc::new(Mutex::new(Inner {
+                conn,
+                max_payload,
+                next_id: 1,
+                streams: HashMap::new(),
+            })),
+            banner: String::from_utf8_lossy(&cnxn.data)
+                .trim_end_matches('\0')
+                .to_string(),
+        }
+    }
+
+    /// The device's banner, e.g. "device::ro.product.name=x;ro.product.model=y;features=z".
+    pub fn banner(&self) -> &str {
+        &self.banner
+    }
+
+    /// The `ro.product.*` properties of the banner.
+    pub fn properties(&self) -> HashMap<String, String> {
+        let properties = self.banner.split("::").nth(1).unwrap_or("");
+        properties
+            .split(';')
+            .filter_map(|property| property.split_once('='))
+            .filter(|(key, _)| *key != "features")
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    pub fn features(&self) -> FeatureSet {
+        let properties = self.banner.split("::").nth(1).unwrap_or("");
+        properties
+            .split(';')
+            .find_map(|property| property.strip_prefix("features="))
+            .map(FeatureSet::parse)
+            .unwrap_or_default()
+    }
+
+    /// Open a stream to `service`, e.g. "shell:ls" or "sync:".
+    pub fn open(&self, service: &str) -> Result<AdbdStream, AdbError> {
+        let mut inner = self.lock()?;
+        let local_id = inner.next_id;
+        inner.next_id += 1;
+        inner.streams.insert(local_id, StreamState::default());
+        let mut data = service.as_bytes().to_vec();
+        data.push(0);
+        inner.send(Message::new(A_OPEN, local_id, 0, &data))?;
+        loop {
+            let stream = &inner.streams[&local_id];
+            if stream.ready {
+                let remote_id = stream.remote_id;
+                return Ok(AdbdStream {
+                    inner: self.inner.clone(),
+                    local_id,
+                    remote_id,
+                });
+            }
+            if stream.closed {
+                inner.streams.remove(&local_id);
+                return Err(AdbError::ResponseStatusError {
+                    content: format!("{}: service refused", service),
+                });
+            }
+            inner.pump()?;
+        }
+    }
+
+    /// Run `cmd` through `shell:` and collect its output.
+    pub fn shell(&self, cmd: &str) -> Result<Vec<u8>, AdbError> {
+        self.open(&format!("shell:{}", cmd))?.read_until_close()
+    }
+
+    /// Run `cmd` through `exec:`, binary output arrives unchanged.
+    pub fn exec(&self, cmd: &str) -> Result<Vec<u8>, AdbError> {
+        self.open(&format!("exec:{}", cmd))?.read_until_close()
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Inner>, AdbError> {
+        lock(&self.inner)
+    }
+}
+
+fn lock(inner: &Mutex<Inner>) -> Result<MutexGuard<'_, Inner>, AdbError> {
+    inner.lock().map_err(|_| AdbError::UnknownError {
+        source: "adbd connection poisoned by a panicked thread".into(),
+    })
+}
+
+/// One stream of an `AdbdClient`, closed when dropped.
+#[derive(Debug)]
+pub struct AdbdStream {
+    inner: Arc<Mutex<Inner>>,
+    local_id: u32,
+    remote_id: u32,
+}
+
+impl AdbdStream {
+    /// Send `data`, waiting for the device to acknowledge every message.
+    pub fn write_bytes(&mut self, data: &[u8]) -> Result<(), AdbError> {
+        let mut inner = lock(&self.inner)?;
+        let max_payload = inner.max_payload;
+        for chunk in data.chunks(max_payload) {
+            match inner.streams.get_mut(&self.local_id) {
+                Some(stream) if !stream.closed => stream.ready = false,
+                _ => return Err(AdbError::ClosedByPeer),
+            }
+            inner.send(Message::new(A_WRTE, self.local_id, self.remote_id, chunk))?;
+            loop {
+                match inner.streams.get(&self.local_id) {
+                    Some(stream) if stream.ready => break,
+                    Some(stream) if !stream.closed => inner.pump()?,
+                    _ => return Err(AdbError::ClosedByPeer),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read whatever arrived, at most `buf.len()` bytes, 0 once the device closed the stream.
+    pub fn read_some(&mut self, buf: &mut [u8]) -> Result<usize, AdbError> {
+        let mut inner = lock(&self.inner)?;
+        loop {
+            let stream = match inner.streams.get_mut(&self.local_id) {
+                Some(stream) => stream,
+                None => return Ok(0),
+            };
+            if !stream.buffer.is_empty() {
+                let n = buf.len().min(stream.buffer.len());
+                for (slot, byte) in buf.iter_mut().zip(stream.buffer.drain(..n)) {
+                    *slot = byte;
+                }
+                if stream.buffer.is_empty() && std::mem::take(&mut stream.unacked) {
+                    inner.send(Message::new(A_OKAY, self.local_id, self.remote_id, &[]))?;
+                }
+                return Ok(n);
+            }
+            if stream.closed {
+                return Ok(0);
+            }
+            inner.pump()?;
+        }
+    }
+
+    pub fn read_until_close(&mut self) -> Result<Vec<u8>, AdbError> {
+        let mut res = Vec::new();
+        let mut buff = vec![0; 64 * 1024];
+        loop {
+            match self.read_some(&mut buff)? {
+                0 => return Ok(res),
+                n => res.extend_from_slice(&buff[..n]),
+            }
+        }
+    }
+
+    pub fn close(&mut self) -> Result<(), AdbError> {
+        let mut inner = lock(&self.inner)?;
+        if let Some(stream) = inner.streams.remove(&self.local_id) {
+            if !stream.closed {
+                inner.send(Message::new(A_CLSE, self.local_id, self.remote_id, &[]))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for AdbdStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_some(buf)
+            .map_err(|error| io::Error::other(error.to_string()))
+    }
+}
+
+impl Write for AdbdStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_bytes(buf)
+            .map_err(|error| io::Error::other(error.to_string()))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for AdbdStream {
+    fn drop(&mut self) {
+        if let Err(error) = self.close() {
+            log::debug!("close stream {}: {}", self.local_id, error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::test::TEST_KEY;
+    use crate::auth::AdbKey;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn expect(stream: &mut impl Read, command: u32) -> Message {
+        let message = Message::read_from(stream).unwrap();
+        assert_eq!(message.command, command, "got {:?}", message);
+        message
+    }
+
+    fn serve<F>(handler: F) -> (AdbdClient, thread::JoinHandle<()>)
+    where
+        F: FnOnce(&mut TcpStream) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let cnxn = expect(&mut stream, A_CNXN);
+            assert_eq!(cnxn.data, b"host::\0");
+            let banner =
+                b"device::ro.product.name=sdk;ro.product.model=Pixel;features=shell_v2,cmd";
+            Message::new(A_CNXN, A_VERSION, 4096, banner)
+                .write_to(&mut stream)
+                .unwrap();
+            handler(&mut stream);
+        });
+        let client =
+            AdbdClient::connect(&addr, time::Duration::from_secs(10), &AdbKeyStore::new()).unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_message_roundtrip() {
+        let message = Message::new(A_WRTE, 1, 2, b"hello");
+        let encoded = message.encode();
+        assert_eq!(&encoded[..4], b"WRTE");
+        assert_eq!(&encoded[16..20], &532u32.to_le_bytes());
+        assert_eq!(Message::read_from(&mut &encoded[..]).unwrap(), message);
+
+        let mut corrupted = encoded.clone();
+        corrupted[HEADER_SIZE] = b'j';
+        assert!(Message::read_from(&mut &corrupted[..]).is_err());
+    }
+
+    #[test]
+    fn test_shell() {
+        let (client, server) = serve(|stream| {
+            let open = expect(stream, A_OPEN);
+            assert_eq!(open.data, b"shell:echo hello\0");
+            let local = open.arg0;
+            Message::new(A_OKAY, 7, local, &[])
+                .write_to(stream)
+                .unwrap();
+            Message::new(A_WRTE, 7, local, b"hel")
+                .write_to(stream)
+                .unwrap();
+            expect(stream, A_OKAY);
+            Message::new(A_WRTE, 7, local, b"lo\n")
+                .write_to(stream)
+                .unwrap();
+            expect(stream, A_OKAY);
+            Message::new(A_CLSE, 7, local, &[])
+                .write_to(stream)
+                .unwrap();
+        });
+        assert_eq!(client.properties()["ro.product.model"], "Pixel");
+        assert!(client.features().shell_v2());
+        assert_eq!(client.shell("echo hello").unwrap(), b"hello\n");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_multiplexed_streams() {
+        let (client, server) = serve(|stream| {
+            let first = expect(stream, A_OPEN).arg0;
+            Message::new(A_OKAY, 100, first, &[])
+                .write_to(stream)
+                .unwrap();
+            let second = expect(stream, A_OPEN).arg0;
+            Message::new(A_OKAY, 200, second, &[])
+                .write_to(stream)
+                .unwrap();
+            // data for the second stream arrives while the first one is read
+            Message::new(A_WRTE, 200, second, b"second")
+                .write_to(stream)
+                .unwrap();
+            Message::new(A_WRTE, 100, first, b"first")
+                .write_to(stream)
+                .unwrap();
+            assert_eq!(expect(stream, A_OKAY).arg0, first);
+            let write = expect(stream, A_WRTE);
+            assert_eq!(
+                (write.arg0, write.arg1, &write.data[..]),
+                (second, 200, &b"input"[..])
+            );
+            Message::new(A_OKAY, 200, second, &[])
+                .write_to(stream)
+                .unwrap();
+            // acknowledged only once it was read
+            assert_eq!(expect(stream, A_OKAY).arg0, second);
+            let close = expect(stream, A_CLSE);
+            assert_eq!(close.arg0, first);
+        });
+        let mut first = client.open("shell:").unwrap();
+        let mut second = client.open("shell:cat").unwrap();
+        let mut buff = [0; 16];
+        let n = first.read_some(&mut buff).unwrap();
+        assert_eq!(&buff[..n], b"first");
+        second.write_bytes(b"input").unwrap();
+        let n = second.read_some(&mut buff).unwrap();
+        assert_eq!(&buff[..n], b"second");
+        drop(first);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_flow_control() {
+        let (client, server) = serve(|stream| {
+            let first = expect(stream, A_OPEN).arg0;
+            Message::new(A_OKAY, 100, first, &[])
+                .write_to(stream)
+                .unwrap();
+            Message::new(A_WRTE, 100, first, b"abcd")
+                .write_to(stream)
+                .unwrap();
+            // half of the data is read, no OKAY comes before the next OPEN
+            let second = expect(stream, A_OPEN).arg0;
+            Message::new(A_OKAY, 200, second, &[])
+                .write_to(stream)
+                .unwrap();
+            let okay = expect(stream, A_OKAY);
+            assert_eq!((okay.arg0, okay.arg1), (first, 100));
+            expect(stream, A_CLSE);
+            expect(stream, A_CLSE);
+        });
+        let mut first = client.open("exec:screencap -p").unwrap();
+        let mut buff = [0; 2];
+        first.read_some(&mut buff).unwrap();
+        assert_eq!(&buff, b"ab");
+        let second = client.open("shell:").unwrap();
+        first.read_some(&mut buff).unwrap();
+        assert_eq!(&buff, b"cd");
+        drop(second);
+        drop(first);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_auth() {
+        let key = AdbKey::from_pem(TEST_KEY).unwrap().with_comment("ci@lab");
+        let rejected = AdbKey::from_pem(TEST_KEY).unwrap();
+        let keys = AdbKeyStore::new().with_key(key.clone()).with_key(rejected);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            expect(&mut stream, A_CNXN);
+            // both signatures get rejected, then the public key is offered
+            for _ in 0..2 {
+                Message::new(A_AUTH, AUTH_TOKEN, 0, &[9; 20])
+                    .write_to(&mut stream)
+                    .unwrap();
+                let signature = expect(&mut stream, A_AUTH);
+                assert_eq!(signature.arg0, AUTH_SIGNATURE);
+                assert_eq!(signature.data.len(), 256);
+            }
+            Message::new(A_AUTH, AUTH_TOKEN, 0, &[9; 20])
+                .write_to(&mut stream)
+                .unwrap();
+            let public_key = expect(&mut stream, A_AUTH);
+            assert_eq!(public_key.arg0, AUTH_RSAPUBLICKEY);
+            Message::new(A_CNXN, A_VERSION, 4096, b"device::features=cmd")
+                .write_to(&mut stream)
+                .unwrap();
+            public_key.data
+        });
+        let client = AdbdClient::connect(&addr, time::Duration::from_secs(10), &keys).unwrap();
+        assert!(client.features().cmd());
+        let mut public_key = key.public_key_string().into_bytes();
+        public_key.push(0);
+        assert_eq!(server.join().unwrap(), public_key);
+    }
+
+    #[test]
+    fn test_stls() {
+        let key = AdbKey::from_pem(TEST_KEY).unwrap();
+        let keys = AdbKeyStore::new().with_key(key);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            expect(&mut stream, A_CNXN);
+            Message::new(A_STLS, A_STLS_VERSION, 0, &[])
+                .write_to(&mut stream)
+                .unwrap();
+            assert_eq!(expect(&mut stream, A_STLS).arg0, A_STLS_VERSION);
+            let mut stream = tls::test::accept(stream);
+            Message::new(A_CNXN, A_VERSION, 4096, b"device::features=cmd")
+                .write_to(&mut stream)
+                .unwrap();
+            let open = expect(&mut stream, A_OPEN);
+            assert_eq!(open.data, b"exec:id\0");
+            for message in [
+                Message::new(A_OKAY, 3, open.arg0, &[]),
+                Message::new(A_WRTE, 3, open.arg0, b"uid=2000(shell)"),
+            ] {
+                message.write_to(&mut stream).unwrap();
+            }
+            expect(&mut stream, A_OKAY);
+            Message::new(A_CLSE, 3, open.arg0, &[])
+                .write_to(&mut stream)
+                .unwrap();
+        });
+        let client = AdbdClient::connect(&addr, time::Duration::from_secs(10), &keys).unwrap();
+        assert!(client.features().cmd());
+        assert_eq!(client.exec("id").unwrap(), b"uid=2000(shell)");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_open_refused() {
+        let (client, server) = serve(|stream| {
+            let local = expect(stream, A_OPEN).arg0;
+            Message::new(A_CLSE, 0, local, &[])
+                .write_to(stream)
+                .unwrap();
+        });
+        assert!(matches!(
+            client.open("jdwp:1234"),
+            Err(AdbError::ResponseStatusError { .. })
+        ));
+        server.join().unwrap();
+    }
+}
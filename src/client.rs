@@ -1,25 +1,26 @@
 use curl::easy::Easy;
-use std::fs::File;
-use std::{env, fs};
 use std::fmt::Debug;
+use std::fs::File;
 use std::io;
-use std::io::{Error, Read, Write};
-use std::net::{Shutdown, TcpStream};
+use std::io::{Read, Write};
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
-use std::thread;
+use std::{env, fs};
 
-use std::process::Command;
 use std::time;
 
 use crate::device::{AdbDevice, ShellMixin};
 use crate::error::*;
-use crate::proto::AdbConnectionOrString;
+use crate::proto::{
+    AdbConnectionOrString, DeviceEvent, DeviceInfo, DeviceState, ForWardItem, ForwardSpec,
+    ShellOutput, TransportSelector, WaitState, WaitTransport,
+};
+use crate::server::{parse_version, AdbServer};
+use crate::tracker::DeviceTracker;
+use crate::transport::{AdbTransport, ServerAddress, DEFAULT_HOST, DEFAULT_PORT};
 
 const OKAY: &str = "OKAY";
 const FAIL: &str = "FAIL";
-const DENT: &str = "DENT";
-const DONE: &str = "DONE";
 
 const WINDOWS: &str = "windows";
 const MAC: &str = "macos";
@@ -28,92 +29,111 @@ const MAC_ADB_URL: &str = "https://cdn.mongona.com/mac/adb";
 const LINUX_ADB_URL: &str = "https://cdn.mongona.com/linux/adb";
 const WIN_ADB_URL: &str = "https://cdn.mongona.com/win";
 
-fn check_server(host: &str, port: usize) -> bool {
-    true
+/// Download to `<out_path>.part` and rename it on success, so a failed download never leaves
+/// a partial file behind that `adb_path` would take for the binary.
+fn download_file(source_url: &str, out_path: PathBuf) -> Result<(), AdbError> {
+    let mut part_name = out_path.file_name().unwrap_or_default().to_os_string();
+    part_name.push(".part");
+    let part_path = out_path.with_file_name(part_name);
+    let res = fetch(source_url, &part_path).and_then(|_| {
+        fs::rename(&part_path, &out_path).map_err(|error| AdbError::FileIoError {
+            source: Box::new(error),
+        })
+    });
+    if res.is_err() {
+        let _ = fs::remove_file(&part_path);
+    }
+    res
 }
 
-fn download_file(source_url: &str, out_path: PathBuf) {
+fn fetch(source_url: &str, out_path: &Path) -> Result<(), AdbError> {
+    let download_error = |error: curl::Error| AdbError::StartAdbFailed {
+        source: Box::new(error),
+    };
+    let mut out_file = File::create(out_path).map_err(|error| AdbError::FileIoError {
+        source: Box::new(error),
+    })?;
     let mut curl = Easy::new();
-    let mut out_file = File::create(out_path).unwrap();
-    curl.url(source_url).unwrap();
-    curl.progress(true).unwrap();
+    curl.url(source_url).map_err(download_error)?;
+    curl.progress(true).map_err(download_error)?;
     curl.progress_function(
         |total_download_bytes, cur_download_bytes, _total_upload_bytes, _cur_upload_bytes| {
-            io::stdout().flush().unwrap();
             if total_download_bytes > 0.0 {
-                println!(
+                log::info!(
                     "download process:{}%",
                     (cur_download_bytes / total_download_bytes * 100.0).round()
                 );
-                io::stdout().flush().unwrap();
-            } else {
-                println!("download process:0%");
-                io::stdout().flush().unwrap();
             }
             true
         },
     )
-    .unwrap();
-    curl.write_function(move |data| {
-        out_file.write(&data).unwrap();
-        Ok(data.len())
+    .map_err(download_error)?;
+    // reporting a short write makes curl abort the transfer
+    curl.write_function(move |data| match out_file.write_all(data) {
+        Ok(_) => Ok(data.len()),
+        Err(_) => Ok(0),
     })
-    .unwrap();
-    curl.perform().unwrap();
+    .map_err(download_error)?;
+    curl.perform().map_err(download_error)?;
+    let status = curl.response_code().map_err(download_error)?;
+    if !(200..300).contains(&status) {
+        return Err(AdbError::StartAdbFailed {
+            source: format!(
+                "downloading {} failed with HTTP status {}",
+                source_url, status
+            )
+            .into(),
+        });
+    }
+    Ok(())
 }
 
-fn adb_path() -> String {
-    let cwd = env::current_dir().unwrap();
-    // let cwd_parent = cwd.parent().unwrap();
+/// Path of the adb binary under ./binaries, downloaded on first use.
+pub(crate) fn adb_path() -> Result<String, AdbError> {
+    let file_error = |error: io::Error| AdbError::FileIoError {
+        source: Box::new(error),
+    };
+    let cwd = env::current_dir().map_err(file_error)?;
     let os = env::consts::OS;
-    let dir = Path::join(&cwd, Path::new("binaries"));
+    let dir = cwd.join("binaries");
     if !dir.exists() {
-        fs::create_dir(dir).unwrap()
-    }
-    let mut adb_path = Path::join(&cwd, Path::new("binaries").join("adb"));
-    if os == WINDOWS {
-        adb_path = Path::join(&cwd, Path::new("binaries").join("adb.exe"))
+        fs::create_dir(&dir).map_err(file_error)?
     }
-    let res_path = adb_path.clone();
+    let adb_path = if os == WINDOWS {
+        dir.join("adb.exe")
+    } else {
+        dir.join("adb")
+    };
     if !adb_path.exists() {
         if os == LINUX {
-            download_file(LINUX_ADB_URL, adb_path)
+            download_file(LINUX_ADB_URL, adb_path.clone())?
         } else if os == WINDOWS {
-            let adb_win_api =  Path::join(&cwd, Path::new("binaries").join("AdbWinApi.dll"));
-            let adb_win_usb_api =  Path::join(&cwd, Path::new("binaries").join("AdbWinUsbApi.dll"));
-            let t1 = thread::spawn(|| download_file((WIN_ADB_URL.to_string() + "/adb.exe").as_str(), adb_path));
-            let t2 = thread::spawn(|| {
-                download_file(
-                    (WIN_ADB_URL.to_string() + "/AdbWinApi.dll").as_str(),
-                    adb_win_api
-                )
-            });
-            let t3 = thread::spawn(|| {
-                download_file(
-                    (WIN_ADB_URL.to_string() + "/AdbWinUsbApi.dll").as_str(),
-                    adb_win_usb_api,
-                )
-            });
-            t1.join().unwrap();
-            t2.join().unwrap();
-            t3.join().unwrap();
+            // adb.exe comes last, its presence means the download is complete
+            for name in ["AdbWinApi.dll", "AdbWinUsbApi.dll"] {
+                download_file(&format!("{}/{}", WIN_ADB_URL, name), dir.join(name))?;
+            }
+            download_file(&format!("{}/adb.exe", WIN_ADB_URL), adb_path.clone())?;
+        } else if os == MAC {
+            download_file(MAC_ADB_URL, adb_path.clone())?
         } else {
-            download_file(MAC_ADB_URL, adb_path);
+            return Err(AdbError::StartAdbFailed {
+                source: format!("no adb download for {}", os).into(),
+            });
         }
-        let metadata = res_path.metadata().unwrap();
-        let mut permissions = metadata.permissions();
+        let mut permissions = adb_path.metadata().map_err(file_error)?.permissions();
         permissions.set_mode(0o777);
-        fs::set_permissions(res_path.clone(), permissions).unwrap();
-
+        fs::set_permissions(&adb_path, permissions).map_err(file_error)?;
     }
-    res_path.to_str().unwrap().to_string()
+    Ok(adb_path.to_string_lossy().to_string())
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AdbClient {
     pub host: String,
     pub port: u32,
     pub socket_time: time::Duration,
+    /// Reach the server over this Unix-domain socket instead of `host:port`.
+    pub unix_socket: Option<PathBuf>,
 }
 
 impl AdbClient {
@@ -122,189 +142,358 @@ impl AdbClient {
             host,
             port,
             socket_time,
+            unix_socket: None,
         }
     }
 
-    pub fn _connect(&self) -> AdbConnection {
-        let mut adb_connection = AdbConnection {
-            host: self.host.clone(),
-            port: self.port,
-            conn: None,
-        };
-        let conn = adb_connection.safe_connect().expect("get _connect error");
-        adb_connection.conn = Some(conn);
-        adb_connection
+    /// A client for the server at `address`.
+    pub fn from_address(address: ServerAddress, socket_time: time::Duration) -> AdbClient {
+        match address {
+            ServerAddress::Tcp { host, port } => AdbClient::new(host, port, socket_time),
+            ServerAddress::Unix(path) => {
+                AdbClient::new(DEFAULT_HOST.to_string(), DEFAULT_PORT, socket_time)
+                    .with_unix_socket(&path)
+            }
+        }
     }
 
-    // Done ✅
-    pub fn server_version(&self) -> i32 {
-        let mut conn = self._connect();
-        conn.send_command("host:version").unwrap();
-        conn.check_oky().unwrap();
-        let res = conn.read_string_block().unwrap();
-        res.parse::<i32>().unwrap() + 16
+    /// A client for the server named by `ADB_SERVER_SOCKET`, `ANDROID_ADB_SERVER_ADDRESS` and
+    /// `ANDROID_ADB_SERVER_PORT`, e.g. the host's server from inside a container.
+    pub fn from_env(socket_time: time::Duration) -> Result<AdbClient, AdbError> {
+        Ok(AdbClient::from_address(
+            ServerAddress::from_env()?,
+            socket_time,
+        ))
     }
 
-    pub fn server_kill(&self) {
-        unimplemented!()
+    pub fn with_unix_socket(mut self, path: &Path) -> AdbClient {
+        self.unix_socket = Some(path.to_path_buf());
+        self
     }
 
-    pub fn wait_for(&self) {
-        unimplemented!()
+    pub fn address(&self) -> ServerAddress {
+        match &self.unix_socket {
+            Some(path) => ServerAddress::Unix(path.clone()),
+            None => ServerAddress::Tcp {
+                host: self.host.clone(),
+                port: self.port,
+            },
+        }
     }
 
-    pub fn connect(&self, addr: &str) -> String {
-        let mut conn = self._connect();
-        conn.send_command(&format!("host:connect:{}", addr))
-            .unwrap();
-        conn.read_string_block().unwrap()
+    /// A new connection to the server, which is started when nothing listens yet.
+    pub fn _connect(&self) -> Result<AdbConnection, AdbError> {
+        let address = self.address();
+        let connect_error = |error: io::Error| AdbError::TcpConnectError {
+            source: Box::new(error),
+        };
+        match address.connect() {
+            Ok(transport) => Ok(AdbConnection::new(transport)),
+            Err(error) if is_not_listening(&error) => {
+                let mut client = self.clone();
+                client.socket_time = time::Duration::ZERO;
+                AdbServer::new(client).start()?;
+                Ok(AdbConnection::new(
+                    address.connect().map_err(connect_error)?,
+                ))
+            }
+            Err(error) => Err(connect_error(error)),
+        }
     }
 
-    pub fn dis_connect(self, addr: &str, raise_err: bool) -> String {
-        let mut conn = self._connect();
-        conn.send_command(&format!("host:disconnect:{}", addr))
-            .unwrap();
-        conn.read_string_block().unwrap()
+    pub fn server_version(&self) -> Result<u32, AdbError> {
+        let mut conn = self._connect()?;
+        conn.send_command("host:version")?;
+        conn.check_oky()?;
+        parse_version(&conn.read_string_block()?)
+    }
+
+    /// Stop the server with `host:kill`, see `AdbServer` for the rest of its lifecycle.
+    pub fn server_kill(&self) -> Result<(), AdbError> {
+        AdbServer::new(self.clone()).kill()
     }
 
-    pub fn shell(&self, serial: &str, commad: &str, stream: bool) -> AdbConnectionOrString {
-        let sn_tid = SerialNTransportID {
-            serial: serial.to_string(),
-            transport_id: 0,
+    /// Block until a device reached `state` on `transport`, or only the device `serial`.
+    /// Gives up after `socket_time`, a zero `socket_time` waits forever.
+    pub fn wait_for(
+        &self,
+        serial: Option<&str>,
+        transport: WaitTransport,
+        state: WaitState,
+    ) -> Result<(), AdbError> {
+        let mut conn = self._connect()?;
+        conn.set_timeout(self.socket_time)?;
+        let prefix = match serial {
+            Some(serial) => format!("host-serial:{}", serial),
+            None => String::from("host"),
         };
-        return self.device(sn_tid).shell(commad, stream, self.socket_time);
-    }
-
-    pub fn devices_list(&self) -> Vec<AdbDevice> {
-        let mut res: Vec<AdbDevice> = Vec::new();
-        let mut c = self._connect();
-        c.send_command("host:devices").unwrap();
-        c.check_oky().unwrap();
-        let out_put = c.read_string_block().unwrap();
-        let out_puts: Vec<&str> = out_put.split("\n").collect();
-        for line in out_puts.into_iter() {
-            let parts: Vec<&str> = line.split("\t").collect();
-            if parts.len() == 2 && parts[1] == "device" {
-                res.push(AdbDevice {
-                    shell_mixin: ShellMixin::new(
-                        AdbClient {
-                            host: self.host.clone(),
-                            port: self.port.clone(),
-                            socket_time: self.socket_time.clone(),
-                        },
-                        parts[0].to_string(),
-                        0,
-                        None,
-                    ),
-                })
-            }
+        conn.send_command(&format!("{}:wait-for-{}-{}", prefix, transport, state))?;
+        // the first OKAY accepts the request, the second one arrives once the state is reached
+        conn.check_oky()?;
+        conn.check_oky()
+    }
+
+    /// Connect the server to a device over TCP/IP, returns the server's message,
+    /// e.g. "already connected to 192.168.1.20:5555".
+    pub fn connect(&self, addr: &str) -> Result<String, AdbError> {
+        let mut conn = self._connect()?;
+        conn.send_command(&format!("host:connect:{}", addr))?;
+        conn.check_oky()?;
+        let content = conn.read_string_block()?;
+        if content.starts_with("failed") || content.starts_with("cannot") {
+            return Err(AdbError::ResponseStatusError { content });
         }
-        res
+        Ok(content)
     }
 
-    pub fn device(&self, sn_tid: SerialNTransportID) -> AdbDevice {
-        if sn_tid.serial != "" || sn_tid.transport_id != 0 {
-            return AdbDevice {
-                shell_mixin: ShellMixin::new(
-                    AdbClient {
-                        host: self.host.clone(),
-                        port: self.port.clone(),
-                        socket_time: self.socket_time.clone(),
-                    },
-                    sn_tid.serial,
-                    sn_tid.transport_id,
-                    None,
-                ),
-            };
+    /// Let the server pair with a device in wireless debugging, `addr` and `code` are the ones
+    /// shown in the device's "Pair device with pairing code" dialog.
+    pub fn pair(&self, addr: &str, code: &str) -> Result<String, AdbError> {
+        let mut conn = self._connect()?;
+        conn.send_command(&format!("host:pair:{}:{}", code, addr))?;
+        conn.check_oky()?;
+        let content = conn.read_string_block()?;
+        if !content.starts_with("Successfully paired") {
+            return Err(AdbError::PairingError { message: content });
         }
-        let serial = env::var("ANDROID_SERIAL").unwrap();
-        if serial != "" {
-            let ds = self.devices_list();
-            if ds.len() == 0 {
-                log::info!("Error: Can't find any android device/emulator")
-            } else if ds.len() > 1 {
-                log::info!("more than one device/emulator, please specify the serial number")
-            } else {
-                return AdbDevice {
-                    shell_mixin: ShellMixin::new(
-                        AdbClient {
-                            host: self.host.clone(),
-                            port: self.port.clone(),
-                            socket_time: self.socket_time.clone(),
-                        },
-                        ds[0].get_serial_no(),
-                        0,
-                        None,
-                    ),
-                };
+        Ok(content)
+    }
+
+    /// With `raise_err` a device that wasn't connected is an error.
+    pub fn dis_connect(&self, addr: &str, raise_err: bool) -> Result<String, AdbError> {
+        let mut conn = self._connect()?;
+        conn.send_command(&format!("host:disconnect:{}", addr))?;
+        match conn.check_oky() {
+            Err(AdbError::ResponseStatusError { content }) if !raise_err => return Ok(content),
+            result => result?,
+        }
+        conn.read_string_block()
+    }
+
+    /// Forward `local` on the host to `remote` on the device `serial`. Returns the port the
+    /// server allocated when `local` is `tcp:0`.
+    pub fn forward(
+        &self,
+        serial: &str,
+        local: &ForwardSpec,
+        remote: &ForwardSpec,
+        norebind: bool,
+    ) -> Result<Option<u16>, AdbError> {
+        let mut conn = self._connect()?;
+        let rebind = if norebind { "norebind:" } else { "" };
+        conn.send_command(&format!(
+            "host-serial:{}:forward:{}{};{}",
+            serial, rebind, local, remote
+        ))?;
+        // the first OKAY accepts the request, the second one reports the forward itself
+        conn.check_oky()?;
+        conn.check_oky()?;
+        read_allocated_port(&mut conn)
+    }
+
+    /// All forwards of all devices.
+    pub fn forward_list(&self) -> Result<Vec<ForWardItem>, AdbError> {
+        let mut conn = self._connect()?;
+        conn.send_command("host:list-forward")?;
+        conn.check_oky()?;
+        let out_put = conn.read_string_block()?;
+        Ok(out_put
+            .lines()
+            .filter_map(|line| {
+                let parts: Vec<&str> = line.split_whitespace().collect();
+                match parts[..] {
+                    [serial, local, remote] => Some(ForWardItem {
+                        serial: serial.to_string(),
+                        local: local.to_string(),
+                        remote: remote.to_string(),
+                    }),
+                    _ => None,
+                }
+            })
+            .collect())
+    }
+
+    /// Remove the forward of `local`, set up for the device `serial`.
+    pub fn forward_remove(&self, serial: &str, local: &ForwardSpec) -> Result<(), AdbError> {
+        let mut conn = self._connect()?;
+        conn.send_command(&format!("host-serial:{}:killforward:{}", serial, local))?;
+        conn.check_oky()?;
+        conn.check_oky()
+    }
+
+    /// Remove the forwards of every device.
+    pub fn forward_remove_all(&self) -> Result<(), AdbError> {
+        let mut conn = self._connect()?;
+        conn.send_command("host:killforward-all")?;
+        conn.check_oky()?;
+        conn.check_oky()
+    }
+
+    pub fn shell(
+        &self,
+        serial: &str,
+        commad: &str,
+        stream: bool,
+    ) -> Result<AdbConnectionOrString, AdbError> {
+        self.serial_device(serial)
+            .shell(commad, stream, self.socket_time)
+    }
+
+    pub fn shell_v2(&self, serial: &str, command: &str) -> Result<ShellOutput, AdbError> {
+        self.serial_device(serial).shell_v2(command)
+    }
+
+    fn serial_device(&self, serial: &str) -> AdbDevice {
+        AdbDevice::new(ShellMixin::new(self.clone(), serial.to_string(), 0, None))
+    }
+
+    /// Stream connect, disconnect and state change events of all devices.
+    pub fn track_devices(&self) -> Result<DeviceTracker, AdbError> {
+        let mut conn = self._connect()?;
+        conn.send_command("host:track-devices-l")?;
+        conn.check_oky()?;
+        Ok(DeviceTracker::new(conn))
+    }
+
+    /// Call `callback` for every device event until it returns `false`.
+    pub fn track_devices_with<F>(&self, mut callback: F) -> Result<(), AdbError>
+    where
+        F: FnMut(DeviceEvent) -> bool,
+    {
+        for event in self.track_devices()? {
+            if !callback(event?) {
+                break;
             }
         }
-        return AdbDevice {
-            shell_mixin: ShellMixin::new(
-                AdbClient {
-                    host: self.host.clone(),
-                    port: self.port.clone(),
-                    socket_time: self.socket_time.clone(),
-                },
-                sn_tid.serial,
-                sn_tid.transport_id,
-                None,
-            ),
+        Ok(())
+    }
+
+    /// Every device the server knows about, whatever its state.
+    pub fn list_devices(&self) -> Result<Vec<DeviceInfo>, AdbError> {
+        let mut conn = self._connect()?;
+        conn.send_command("host:devices-l")?;
+        conn.check_oky()?;
+        let output = conn.read_string_block()?;
+        Ok(output.lines().filter_map(DeviceInfo::parse).collect())
+    }
+
+    /// The devices ready for use, i.e. in the `device` state.
+    pub fn devices_list(&self) -> Result<Vec<AdbDevice>, AdbError> {
+        Ok(self
+            .list_devices()?
+            .into_iter()
+            .filter(|info| info.state == DeviceState::Device)
+            .map(|info| {
+                AdbDevice::new(ShellMixin::new(
+                    self.clone(),
+                    info.serial,
+                    info.transport_id.unwrap_or(0) as i32,
+                    None,
+                ))
+            })
+            .collect())
+    }
+
+    /// Resolve `selector` against the connected devices, `Any`, `Usb` and `Local` only pick
+    /// among those in the `device` state.
+    pub fn device(&self, selector: TransportSelector) -> Result<AdbDevice, AdbError> {
+        let mut matches: Vec<DeviceInfo> = self
+            .list_devices()?
+            .into_iter()
+            .filter(|info| {
+                // like the server, devices that can't be used don't make the choice ambiguous
+                let ready = info.state == DeviceState::Device;
+                match &selector {
+                    TransportSelector::Serial(serial) => &info.serial == serial,
+                    TransportSelector::TransportId(id) => info.transport_id == Some(*id),
+                    TransportSelector::Any => ready,
+                    TransportSelector::Usb => ready && info.usb.is_some(),
+                    TransportSelector::Local => ready && info.usb.is_none(),
+                }
+            })
+            .collect();
+        let info = match (matches.pop(), matches.is_empty()) {
+            (Some(info), true) => info,
+            (Some(_), false) => return Err(AdbError::MoreThanOneDevice),
+            (None, _) => {
+                return Err(match selector {
+                    TransportSelector::Serial(serial) => AdbError::DeviceNotFound { serial },
+                    TransportSelector::TransportId(id) => AdbError::DeviceNotFound {
+                        serial: format!("transport_id:{}", id),
+                    },
+                    _ => AdbError::NoDevice,
+                })
+            }
         };
+        Ok(AdbDevice::new(ShellMixin::new(
+            self.clone(),
+            info.serial,
+            info.transport_id.unwrap_or(0) as i32,
+            None,
+        )))
+    }
+
+    /// The device named by `ANDROID_SERIAL`, or the only connected one like the adb tool.
+    pub fn default_device(&self) -> Result<AdbDevice, AdbError> {
+        self.device(TransportSelector::from_env())
+    }
+
+    /// A connection switched over to the device picked by `selector`.
+    pub fn transport(&self, selector: &TransportSelector) -> Result<AdbConnection, AdbError> {
+        let mut conn = self._connect()?;
+        conn.set_timeout(self.socket_time)?;
+        conn.send_command(&selector.transport_request())?;
+        conn.check_oky()?;
+        Ok(conn)
+    }
+}
+
+/// The port allocated for a `tcp:0` forward or reverse, `None` when the other side closed
+/// the connection without sending one.
+pub(crate) fn read_allocated_port(conn: &mut AdbConnection) -> Result<Option<u16>, AdbError> {
+    let payload = conn.read_bytes_until_close()?;
+    if payload.is_empty() {
+        return Ok(None);
+    }
+    let (length, port) = payload.split_at(payload.len().min(4));
+    let length = std::str::from_utf8(length)
+        .ok()
+        .and_then(|length| usize::from_str_radix(length, 16).ok());
+    if length != Some(port.len()) {
+        return Err(AdbError::ResponseStatusError {
+            content: format!(
+                "invalid port block: {:?}",
+                String::from_utf8_lossy(&payload)
+            ),
+        });
+    }
+    match into_string(port.to_vec())?.trim().parse::<u16>() {
+        Ok(port) => Ok(Some(port)),
+        Err(error) => Err(AdbError::ParseResponseError {
+            source: Box::new(error),
+        }),
     }
 }
 
-pub struct SerialNTransportID {
-    pub serial: String,
-    pub transport_id: i32,
+/// A refused TCP connection, or a Unix socket that doesn't exist (yet).
+pub(crate) fn is_not_listening(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound
+    )
 }
 
 #[derive(Debug)]
 pub struct AdbConnection {
-    host: String,
-    port: u32,
-    conn: Option<TcpStream>,
+    conn: Option<Box<dyn AdbTransport>>,
 }
 
 impl AdbConnection {
-    fn safe_connect(&self) -> Result<TcpStream, AdbError> {
-        let conn = match self.create_socket() {
-            Ok(conn) => conn,
-            Err(error) => {
-                return match error.kind() {
-                    io::ErrorKind::ConnectionRefused => {
-                        match Command::new(&adb_path()).arg("start-server").output() {
-                            Ok(response) => {
-                                if response.status.success() {
-                                    let content = String::from_utf8_lossy(&response.stdout);
-                                    log::info!("start-server done! {}", content);
-                                    let conn = match self.create_socket() {
-                                        Ok(conn) => conn,
-                                        _ => {
-                                            return Err(AdbError::UnknownError {
-                                                source: Box::new(error),
-                                            });
-                                        }
-                                    };
-                                    return Ok(conn);
-                                }
-                                let error = String::from_utf8_lossy(&response.stderr);
-                                Err(AdbError::ResponseStatusError {
-                                    content: String::from(error.clone()),
-                                })
-                            }
-                            Err(error) => Err(AdbError::StartAdbFailed {
-                                source: Box::new(error),
-                            }),
-                        }
-                    }
-                    _ => Err(AdbError::UnknownError {
-                        source: Box::new(error),
-                    }),
-                }
-            }
-        };
-        Ok(conn)
+    /// Speak the smart-socket protocol over `transport`, e.g. a `MemoryTransport` in tests.
+    pub fn new(transport: Box<dyn AdbTransport>) -> AdbConnection {
+        AdbConnection {
+            conn: Some(transport),
+        }
     }
 
     pub fn set_timeout(&self, time_out: time::Duration) -> Result<(), AdbError> {
@@ -329,62 +518,79 @@ impl AdbConnection {
                     };
                 }
                 None => {
-                    panic!("time_out with nil conn!");
+                    return Err(AdbError::TcpConnectError {
+                        source: "set timeout on a closed connection".into(),
+                    });
                 }
             }
         }
-        return Ok(());
+        Ok(())
     }
 
-    fn create_socket(&self) -> Result<TcpStream, io::Error> {
-        let host_port = format!("{}:{}", self.host, self.port);
-        let conn = TcpStream::connect(host_port.clone());
-        conn
+    /// Shut the connection down, later reads and writes fail.
+    pub fn close(&mut self) -> Result<(), AdbError> {
+        match self.conn.take() {
+            Some(conn) => conn.shutdown().map_err(|error| AdbError::TcpConnectError {
+                source: Box::new(error),
+            }),
+            None => Ok(()),
+        }
     }
 
-    fn close(&self) {
-        match &self.conn {
-            Some(conn) => {
-                conn.shutdown(Shutdown::Both)
-                    .expect("close adb server Error!");
-            }
-            None => {}
+    /// Read exactly `n` bytes.
+    pub fn read_bytes(&mut self, n: usize) -> Result<Vec<u8>, AdbError> {
+        let mut buff = vec![0; n];
+        match &mut self.conn {
+            Some(conn) => match conn.read_exact(&mut buff) {
+                Ok(_) => Ok(buff),
+                Err(error) => Err(AdbError::from_read(error)),
+            },
+            None => Err(AdbError::TcpReadError {
+                source: "read from a closed connection".into(),
+            }),
         }
     }
 
-    fn read(&mut self, n: usize) -> Result<String, AdbError> {
-        self.read_full(n)
+    pub fn send_bytes(&mut self, data: &[u8]) -> Result<(), AdbError> {
+        match &mut self.conn {
+            Some(conn) => match conn.write_all(data) {
+                Ok(_) => Ok(()),
+                Err(error) => Err(AdbError::from_write(error)),
+            },
+            None => Err(AdbError::TcpWriteError {
+                source: "write to a closed connection".into(),
+            }),
+        }
     }
 
-    fn read_full(&mut self, n: usize) -> Result<String, AdbError> {
-        let mut buff = vec![0; n];
-        match &mut self.conn {
-            Some(conn) => {
-                match conn.read_exact(&mut buff) {
-                    Ok(_) => {}
-                    Err(error) => {
-                        return Err(AdbError::TcpReadError {
-                            source: Box::new(error),
-                        });
-                    }
-                };
-
-                match String::from_utf8(Vec::from(buff)) {
-                    Ok(content_string) => {
-                        println!("read: => {}", content_string);
-                        return Ok(content_string);
-                    }
-                    Err(error) => {
-                        return Err(AdbError::ParseResponseError {
-                            source: Box::new(error),
-                        });
-                    }
-                }
-            }
+    /// Read a block prefixed with its length as 4 hex digits.
+    pub fn read_block(&mut self) -> Result<Vec<u8>, AdbError> {
+        let length = self.read_bytes(4)?;
+        let size = match std::str::from_utf8(&length)
+            .ok()
+            .and_then(|length| usize::from_str_radix(length, 16).ok())
+        {
+            Some(size) => size,
             None => {
-                println!("{:?}", "NoneNoneNoneNoneNone");
-                Ok("".to_string())
+                return Err(AdbError::ResponseStatusError {
+                    content: format!(
+                        "invalid block length: {:?}",
+                        String::from_utf8_lossy(&length)
+                    ),
+                })
             }
+        };
+        self.read_bytes(size)
+    }
+
+    pub fn read_bytes_until_close(&mut self) -> Result<Vec<u8>, AdbError> {
+        let mut res = Vec::new();
+        match &mut self.conn {
+            Some(conn) => match conn.read_to_end(&mut res) {
+                Ok(_) => Ok(res),
+                Err(error) => Err(AdbError::from_read(error)),
+            },
+            None => Ok(res),
         }
     }
 
@@ -396,92 +602,440 @@ impl AdbConnection {
 
     pub fn send_command(&mut self, cmd: &str) -> Result<(), AdbError> {
         let msg = self.add_command_length_prefix(cmd.to_string());
-        match &mut self.conn {
-            Some(conn) => match conn.write_all(msg.as_ref()) {
-                Ok(_) => Ok(()),
-                Err(error) => Err(AdbError::TcpWriteError {
-                    source: Box::new(error),
-                }),
-            },
-            None => Ok(()),
-        }
+        self.send_bytes(msg.as_bytes())
     }
 
-    fn read_string(&mut self, n: usize) -> String {
-        let res = match self.read(n) {
-            Ok(res) => res,
-            Err(error) => {
-                println!("{:?}", error);
-                String::new()
-            }
-        };
-        res
+    fn read_string(&mut self, n: usize) -> Result<String, AdbError> {
+        into_string(self.read_bytes(n)?)
     }
 
     pub fn read_string_block(&mut self) -> Result<String, AdbError> {
-        let res = self.read_string(4);
-        if res.len() == 0 {
-            return Err(AdbError::ResponseStatusError {
-                content: String::from("receive data error connection closed"),
-            });
-        }
-        let size = res.parse::<usize>().unwrap();
-        let res = self.read_string(size);
-        Ok(res)
+        into_string(self.read_block()?)
     }
 
     pub fn read_until_close(&mut self) -> Result<String, AdbError> {
-        let mut res = String::new();
-        loop {
-            let mut origin_buffer = self.read(4096);
-            let buffer = match origin_buffer {
-                Ok(r) => {
-                    res += &*r;
-                    r
-                }
-                Err(error) => String::new(),
-            };
-            if buffer.len() == 0 {
-                break;
-            }
-        }
-        Ok(res.to_string())
+        let res = self.read_bytes_until_close()?;
+        Ok(String::from_utf8_lossy(&res).to_string())
     }
 
+    /// Read the 4 byte status, a FAIL is mapped to a typed error by its message.
     pub fn check_oky(&mut self) -> Result<(), AdbError> {
-        let data = self.read_string(4);
-        if data == FAIL {
-            log::debug!("receive data: {} connection closed", data)
-        } else if data == OKAY {
+        let data = self.read_string(4)?;
+        if data == OKAY {
             return Ok(());
         }
-        Ok(())
+        if data == FAIL {
+            let message = String::from_utf8_lossy(&self.read_block()?).to_string();
+            log::debug!("receive FAIL: {}", message);
+            return Err(AdbError::from_fail(message));
+        }
+        Err(AdbError::ResponseStatusError {
+            content: format!("unexpected status: {:?}", data),
+        })
+    }
+}
+
+fn into_string(data: Vec<u8>) -> Result<String, AdbError> {
+    match String::from_utf8(data) {
+        Ok(content) => Ok(content),
+        Err(error) => Err(AdbError::ParseResponseError {
+            source: Box::new(error),
+        }),
+    }
+}
+
+/// Raw access to the remaining stream, e.g. the output of `exec:` or a `shell:` in stream mode.
+impl Read for AdbConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.conn {
+            Some(conn) => conn.read(buf),
+            None => Ok(0),
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::AdbClient;
+    use super::{AdbClient, AdbConnection};
+    use crate::error::AdbError;
+    use crate::proto::{
+        AdbConnectionOrString, DeviceInfo, DeviceState, ForwardSpec, TransportSelector, WaitState,
+        WaitTransport,
+    };
+    use crate::testing::{MockDevice, MockServer};
+    use crate::transport::{MemoryTransport, ServerAddress};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
     use std::time;
 
+    fn connection_with(response: &[u8]) -> AdbConnection {
+        AdbConnection::new(Box::new(MemoryTransport::new(response)))
+    }
+
+    #[test]
+    fn test_read_block() {
+        let mut payload = b"0018emulator-5554\tdevice\nabc".to_vec();
+        payload.extend_from_slice(&[0, 0xff]);
+        let mut conn = connection_with(&payload);
+        assert_eq!(
+            conn.read_string_block().unwrap(),
+            "emulator-5554\tdevice\nabc"
+        );
+        assert_eq!(conn.read_bytes(2).unwrap(), [0, 0xff]);
+    }
+
     #[test]
+    fn test_check_oky_fail() {
+        let mut conn =
+            connection_with(b"OKAYFAIL0017device 'R58M' not foundFAIL000edevice offline");
+        conn.check_oky().unwrap();
+        match conn.check_oky() {
+            Err(AdbError::DeviceNotFound { serial }) => assert_eq!(serial, "R58M"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(matches!(conn.check_oky(), Err(AdbError::DeviceOffline)));
+        // the server hung up
+        assert!(matches!(conn.check_oky(), Err(AdbError::ClosedByPeer)));
+    }
+
+    #[test]
+    fn test_forward() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let server = std::thread::spawn(move || {
+            let mut commands = Vec::new();
+            for response in [
+                &b"OKAYOKAY000545123"[..],
+                b"OKAYOKAY",
+                b"OKAYOKAY0005451",
+                b"OKAY002demulator-5554 tcp:45123 localabstract:scrcpy\n",
+                b"OKAYOKAY",
+            ] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut length = [0; 4];
+                stream.read_exact(&mut length).unwrap();
+                let length =
+                    usize::from_str_radix(std::str::from_utf8(&length).unwrap(), 16).unwrap();
+                let mut command = vec![0; length];
+                stream.read_exact(&mut command).unwrap();
+                commands.push(String::from_utf8(command).unwrap());
+                stream.write_all(response).unwrap();
+            }
+            commands
+        });
+
+        let adb = AdbClient::new(String::from("127.0.0.1"), port, time::Duration::new(10, 0));
+        let local = ForwardSpec::Tcp(0);
+        let remote = ForwardSpec::LocalAbstract(String::from("scrcpy"));
+        assert_eq!(
+            adb.forward("emulator-5554", &local, &remote, true).unwrap(),
+            Some(45123)
+        );
+        // no port is sent for a fixed local port, a cut off one is an error
+        let local = ForwardSpec::Tcp(8080);
+        assert_eq!(
+            adb.forward("emulator-5554", &local, &remote, false)
+                .unwrap(),
+            None
+        );
+        assert!(matches!(
+            adb.forward("emulator-5554", &ForwardSpec::Tcp(0), &remote, false),
+            Err(AdbError::ResponseStatusError { .. })
+        ));
+        let items = adb.forward_list().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].serial, "emulator-5554");
+        assert_eq!(items[0].local, "tcp:45123");
+        assert_eq!(items[0].remote, "localabstract:scrcpy");
+        adb.forward_remove("emulator-5554", &ForwardSpec::Tcp(45123))
+            .unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                "host-serial:emulator-5554:forward:norebind:tcp:0;localabstract:scrcpy",
+                "host-serial:emulator-5554:forward:tcp:8080;localabstract:scrcpy",
+                "host-serial:emulator-5554:forward:tcp:0;localabstract:scrcpy",
+                "host:list-forward",
+                "host-serial:emulator-5554:killforward:tcp:45123",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_list_devices() {
+        let output = "emulator-5554          device product:sdk_gphone64 model:sdk_gphone64 device:emu64a transport_id:1\n\
+            R58M                   unauthorized usb:1-1 transport_id:2\n\
+            0123456789ABCDEF       no permissions (missing udev rules? user is in the plugdev group); see [http://developer.android.com/tools/device.html] usb:1-2 transport_id:3\n\
+            192.168.1.20:5555      offline transport_id:4\n";
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut command = [0; 18];
+            stream.read_exact(&mut command).unwrap();
+            write!(stream, "OKAY{:04x}{}", output.len(), output).unwrap();
+            String::from_utf8(command.to_vec()).unwrap()
+        });
+
+        let adb = AdbClient::new(String::from("127.0.0.1"), port, time::Duration::new(10, 0));
+        let devices = adb.list_devices().unwrap();
+        assert_eq!(server.join().unwrap(), "000Ehost:devices-l");
+        assert_eq!(
+            devices[0],
+            DeviceInfo {
+                serial: String::from("emulator-5554"),
+                state: DeviceState::Device,
+                product: Some(String::from("sdk_gphone64")),
+                model: Some(String::from("sdk_gphone64")),
+                device: Some(String::from("emu64a")),
+                transport_id: Some(1),
+                usb: None,
+            }
+        );
+        let states: Vec<_> = devices
+            .iter()
+            .map(|info| {
+                (
+                    info.serial.as_str(),
+                    info.state.clone(),
+                    info.usb.as_deref(),
+                    info.transport_id,
+                )
+            })
+            .collect();
+        assert_eq!(
+            states[1..],
+            [
+                ("R58M", DeviceState::Unauthorized, Some("1-1"), Some(2)),
+                (
+                    "0123456789ABCDEF",
+                    DeviceState::NoPermissions,
+                    Some("1-2"),
+                    Some(3)
+                ),
+                ("192.168.1.20:5555", DeviceState::Offline, None, Some(4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_device_selector() {
+        let output = "emulator-5554\tdevice transport_id:1\nR58M\tdevice usb:1-1 transport_id:2\n\
+            emulator-5556\toffline transport_id:3\n0123\tunauthorized usb:1-2 transport_id:4\n";
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut command = [0; 18];
+                stream.read_exact(&mut command).unwrap();
+                write!(stream, "OKAY{:04x}{}", output.len(), output).unwrap();
+            }
+        });
+
+        let adb = AdbClient::new(String::from("127.0.0.1"), port, time::Duration::new(10, 0));
+        let device = adb.device(TransportSelector::Usb).unwrap();
+        assert_eq!(device.shell_mixin.serial, "R58M");
+        assert_eq!(device.shell_mixin.transport_id, 2);
+        let device = adb.device(TransportSelector::Local).unwrap();
+        assert_eq!(device.shell_mixin.serial, "emulator-5554");
+        let device = adb.device(TransportSelector::TransportId(1)).unwrap();
+        assert_eq!(device.shell_mixin.serial, "emulator-5554");
+        let device = adb.device(TransportSelector::TransportId(3)).unwrap();
+        assert_eq!(device.shell_mixin.serial, "emulator-5556");
+        assert!(matches!(
+            adb.device(TransportSelector::Any),
+            Err(AdbError::MoreThanOneDevice)
+        ));
+        match adb.device(TransportSelector::Serial(String::from("4567"))) {
+            Err(AdbError::DeviceNotFound { serial }) => assert_eq!(serial, "4567"),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_wait_for() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let server = std::thread::spawn(move || {
+            let mut streams = Vec::new();
+            for response in [&b"OKAYOKAY"[..], b"OKAY"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut command = [0; 4];
+                stream.read_exact(&mut command).unwrap();
+                let mut command =
+                    vec![
+                        0;
+                        usize::from_str_radix(std::str::from_utf8(&command).unwrap(), 16).unwrap()
+                    ];
+                stream.read_exact(&mut command).unwrap();
+                stream.write_all(response).unwrap();
+                streams.push((String::from_utf8(command).unwrap(), stream));
+            }
+            streams
+                .into_iter()
+                .map(|(command, _)| command)
+                .collect::<Vec<_>>()
+        });
+
+        let adb = AdbClient::new(
+            String::from("127.0.0.1"),
+            port,
+            time::Duration::from_millis(200),
+        );
+        adb.wait_for(Some("emulator-5554"), WaitTransport::Any, WaitState::Device)
+            .unwrap();
+        // the device never shows up, the second OKAY is missing
+        assert!(adb
+            .wait_for(None, WaitTransport::Usb, WaitState::Bootloader)
+            .is_err());
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                "host-serial:emulator-5554:wait-for-any-device",
+                "host:wait-for-usb-bootloader"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pair() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let server = std::thread::spawn(move || {
+            let mut commands = Vec::new();
+            for response in [
+                &b"OKAY0036Successfully paired to 192.168.1.20:37215 [guid=adb-1]"[..],
+                b"OKAY0031Failed: Wrong password or connection was dropped.",
+            ] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut length = [0; 4];
+                stream.read_exact(&mut length).unwrap();
+                let mut command =
+                    vec![
+                        0;
+                        usize::from_str_radix(std::str::from_utf8(&length).unwrap(), 16).unwrap()
+                    ];
+                stream.read_exact(&mut command).unwrap();
+                stream.write_all(response).unwrap();
+                commands.push(String::from_utf8(command).unwrap());
+            }
+            commands
+        });
+
+        let adb = AdbClient::new(
+            String::from("127.0.0.1"),
+            port,
+            time::Duration::from_secs(2),
+        );
+        assert_eq!(
+            adb.pair("192.168.1.20:37215", "123456").unwrap(),
+            "Successfully paired to 192.168.1.20:37215 [guid=adb-1]"
+        );
+        match adb.pair("192.168.1.20:37215", "654321") {
+            Err(AdbError::PairingError { message }) => assert!(message.starts_with("Failed")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                "host:pair:123456:192.168.1.20:37215",
+                "host:pair:654321:192.168.1.20:37215"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_address() {
+        let server = MockServer::start().unwrap();
+        let address: ServerAddress = format!("tcp:127.0.0.1:{}", server.port()).parse().unwrap();
+        let adb = AdbClient::from_address(address.clone(), time::Duration::from_secs(2));
+        assert_eq!(adb.address(), address);
+        assert_eq!(adb.server_version().unwrap(), 41);
+
+        let address: ServerAddress = "localfilesystem:/run/adb.sock".parse().unwrap();
+        let adb = AdbClient::from_address(address.clone(), time::Duration::from_secs(2));
+        assert_eq!(adb.address(), address);
+    }
+
+    #[test]
+    fn test_download_file() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/adb", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            for response in [
+                &b"HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\n\r\nnot found"[..],
+                b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nELF",
+            ] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut byte = [0; 1];
+                while !request.ends_with(b"\r\n\r\n") {
+                    stream.read_exact(&mut byte).unwrap();
+                    request.push(byte[0]);
+                }
+                stream.write_all(response).unwrap();
+            }
+        });
+
+        let dir = std::env::temp_dir().join(format!("adbutils-download-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let adb = dir.join("adb");
+        match super::download_file(&url, adb.clone()) {
+            Err(AdbError::StartAdbFailed { source }) => assert!(source.to_string().contains("404")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(!adb.exists());
+        assert!(!dir.join("adb.part").exists());
+        super::download_file(&url, adb.clone()).unwrap();
+        assert_eq!(std::fs::read(&adb).unwrap(), b"ELF");
+        assert!(!dir.join("adb.part").exists());
+        server.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[ignore = "downloads adb over the network"]
     fn test_path() {
-        println!("{:?}", super::adb_path())
+        let path = super::adb_path().unwrap();
+        assert!(std::path::Path::new(&path).is_file());
     }
 
     #[test]
     fn test_connect() {
-        let adb = AdbClient::new(String::from("localhost"), 5037, time::Duration::new(10, 0));
-        // let client = adb._connect();
-        println!("adb version: {:?}", adb.server_version())
+        let server = MockServer::start().unwrap();
+        let adb = server.client();
+        assert_eq!(adb.server_version().unwrap(), 41);
+        assert_eq!(
+            adb.connect("192.168.1.20:5555").unwrap(),
+            "connected to 192.168.1.20:5555"
+        );
+        assert_eq!(
+            adb.connect("192.168.1.20:5555").unwrap(),
+            "already connected to 192.168.1.20:5555"
+        );
+        assert_eq!(
+            adb.dis_connect("192.168.1.20:5555", true).unwrap(),
+            "disconnected 192.168.1.20:5555"
+        );
+        assert!(adb.dis_connect("192.168.1.20:5555", true).is_err());
+        assert!(adb.dis_connect("192.168.1.20:5555", false).is_ok());
     }
+
     #[test]
     fn test_devices() {
-        let adb = AdbClient::new(String::from("localhost"), 5037, time::Duration::new(10, 0));
-        println!("{:?}", adb.devices_list());
-        println!(
-            "{:?}",
-            adb.devices_list()[0].shell("ls -a ", false, time::Duration::new(0, 0))
-        )
+        let server = MockServer::start().unwrap();
+        server.add_device(MockDevice::new("emulator-5554").shell("ls -a", ".\n..\nsdcard\n"));
+        server.add_device(MockDevice::new("emulator-5556").state(DeviceState::Offline));
+        let adb = server.client();
+        let devices = adb.devices_list().unwrap();
+        assert_eq!(devices.len(), 1);
+        match devices[0]
+            .shell("ls -a", false, time::Duration::new(0, 0))
+            .unwrap()
+        {
+            AdbConnectionOrString::String(output) => assert_eq!(output, ".\n..\nsdcard\n"),
+            other => panic!("unexpected result: {:?}", other),
+        }
     }
 }
@@ -1,16 +1,33 @@
-use crate::client::{AdbClient, AdbConnection};
+use crate::client::{read_allocated_port, AdbClient, AdbConnection};
 use crate::error::AdbError;
-use crate::proto::AdbConnectionOrString;
+use crate::proto::{
+    AdbConnectionOrString, FeatureSet, ForWardItem, ForwardSpec, ReverseItem, ShellOutput,
+    TransportSelector,
+};
+use crate::shell::{self, ShellSession};
+use crate::sync::Sync;
 use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::OnceLock;
 use std::time;
 
-#[derive(Debug)]
+// what the placeholders of the original API return until they get implemented
+fn not_implemented(name: &str) -> AdbError {
+    AdbError::ResponseStatusError {
+        content: format!("ShellMixin::{} is not implemented", name),
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ShellMixin {
     pub client: AdbClient,
     pub serial: String,
     pub transport_id: i32,
     pub properties: Option<HashMap<String, String>>,
+    features: OnceLock<FeatureSet>,
 }
 
 impl ShellMixin {
@@ -25,30 +42,51 @@ impl ShellMixin {
             serial,
             transport_id,
             properties,
+            features: OnceLock::new(),
         }
     }
 
-    pub fn run(&self, cmd: String) -> AdbConnectionOrString {
-        self.client.shell(self.serial.as_str(), cmd.as_str(), false)
+    /// Use `features` instead of querying them from the device.
+    pub fn with_features(mut self, features: FeatureSet) -> ShellMixin {
+        self.features = OnceLock::from(features);
+        self
     }
 
-    pub fn say_hello(&self) -> String {
-        let content = "hello from ".to_string() + &self.serial;
-        let res = self.run("echo ".to_string() + content.as_str());
-        match res {
-            AdbConnectionOrString::String(str) => str,
-            _ => String::from("err say_hello"),
+    /// Parsed `features` of the device, queried once and kept by clones.
+    pub fn features(&self) -> Result<&FeatureSet, AdbError> {
+        if let Some(features) = self.features.get() {
+            return Ok(features);
+        }
+        let mut conn = self.open_transport("features", self.client.socket_time)?;
+        let features = FeatureSet::parse(&conn.read_string_block()?);
+        Ok(self.features.get_or_init(|| features))
+    }
+
+    pub fn run(&self, cmd: String) -> Result<String, AdbError> {
+        match self.shell(cmd.as_str(), false, self.client.socket_time)? {
+            AdbConnectionOrString::String(output) => Ok(output),
+            AdbConnectionOrString::AdbConnection(mut conn) => conn.read_until_close(),
         }
     }
 
-    pub fn switch_screen(&self, status: bool) {
-        let key_map: HashMap<bool, &str> = HashMap::from([(true, "224"), (false, "223")]);
-        let cmd = key_map.get(&status).unwrap();
+    /// Like `run` but keeps stdout, stderr and the exit code apart.
+    pub fn run_v2(&self, cmd: &str) -> Result<ShellOutput, AdbError> {
+        self.shell_v2(cmd)
     }
 
-    pub fn switch_air_plane(&self, status: bool) {
-        let mut base = "settings put global airplane_mode_on".to_string();
-        let mut am = "am broadcast -a android.intent.action.AIRPLANE_MODE --ez state".to_string();
+    pub fn say_hello(&self) -> Result<String, AdbError> {
+        let content = "hello from ".to_string() + &self.serial;
+        self.run("echo ".to_string() + content.as_str())
+    }
+
+    pub fn switch_screen(&self, status: bool) -> Result<(), AdbError> {
+        let key_code = if status { "224" } else { "223" };
+        self.key_event(key_code).map(|_| ())
+    }
+
+    pub fn switch_air_plane(&self, status: bool) -> Result<(), AdbError> {
+        let mut base = "settings put global airplane_mode_on ".to_string();
+        let mut am = "am broadcast -a android.intent.action.AIRPLANE_MODE --ez state ".to_string();
         if status {
             base += "1";
             am += "true"
@@ -56,170 +94,263 @@ impl ShellMixin {
             base += "0";
             am += "false"
         }
-        self.run(base.to_string());
-        self.run(am.to_string());
-    }
-
-    pub fn switch_wifi(&self, status: bool) {
-        let cmd_map: HashMap<bool, String> = HashMap::from([
-            (true, "svc wifi enable".to_string()),
-            (false, "svc wifi disable".to_string()),
-        ]);
-        let cmd = cmd_map.get(&status).unwrap();
-        self.run(String::from(cmd));
-    }
-    pub fn key_event(&self, key_code: &str) -> String {
-        let res = self.run("input keyevent ".to_string() + key_code);
-        match res {
-            AdbConnectionOrString::String(str) => str,
-            _ => String::from("err key_event"),
-        }
+        self.run(base)?;
+        self.run(am)?;
+        Ok(())
+    }
+
+    pub fn switch_wifi(&self, status: bool) -> Result<(), AdbError> {
+        let cmd = if status {
+            "svc wifi enable"
+        } else {
+            "svc wifi disable"
+        };
+        self.run(String::from(cmd)).map(|_| ())
+    }
+
+    pub fn key_event(&self, key_code: &str) -> Result<String, AdbError> {
+        self.run("input keyevent ".to_string() + key_code)
     }
 
-    pub fn click(&self, x: i32, y: i32) {
-        self.run(format!("input tap {} {}", x, y));
+    pub fn click(&self, x: i32, y: i32) -> Result<(), AdbError> {
+        self.run(format!("input tap {} {}", x, y)).map(|_| ())
     }
 
-    pub fn swipe(&self, x: i32, y: i32, tox: i32, toy: i32, duration: time::Duration) {
+    pub fn swipe(
+        &self,
+        x: i32,
+        y: i32,
+        tox: i32,
+        toy: i32,
+        duration: time::Duration,
+    ) -> Result<(), AdbError> {
         self.run(format!(
             "input swipe {} {} {} {} {}",
             x,
             y,
             tox,
             toy,
-            duration.as_secs() * 1000
-        ));
+            duration.as_millis()
+        ))
+        .map(|_| ())
     }
 
-    pub fn send_keys(&self, text: &str) {
-        self.run(format!("input text {}", text));
+    pub fn send_keys(&self, text: &str) -> Result<(), AdbError> {
+        self.run(format!("input text {}", text)).map(|_| ())
     }
 
-    pub fn escape_special_characters(&self, text: &str) {}
-
-    pub fn wlan_ip(&self) -> String {
-        let res = self.run("ifconfig wlan0".to_string());
-        match res {
-            AdbConnectionOrString::String(str) => str,
-            _ => String::from("err wlan_ip"),
-        }
+    pub fn escape_special_characters(&self, _text: &str) -> Result<String, AdbError> {
+        Err(not_implemented("escape_special_characters"))
     }
 
-    pub fn install(&self) {
-        unimplemented!()
+    pub fn wlan_ip(&self) -> Result<String, AdbError> {
+        self.run("ifconfig wlan0".to_string())
     }
 
-    pub fn install_remote(&self) {
-        unimplemented!()
+    pub fn install(&self) -> Result<(), AdbError> {
+        Err(not_implemented("install"))
     }
 
-    pub fn uninstall(&self, package_name: &str) {
-        self.run(format!("pm uninstall {}", package_name));
+    /// Install an apk that is already on the device, returns the `pm` output.
+    pub fn install_remote(&self, remote_path: &str) -> Result<String, AdbError> {
+        self.run(format!("pm install -r -t {}", remote_path))
     }
 
-    pub fn get_prop(&self, prop: &str) -> String {
-        let res = self.run("getprop ".to_string() + prop);
-        match res {
-            AdbConnectionOrString::String(str) => str,
-            _ => String::from("err get_prop"),
+    pub fn uninstall(&self, package_name: &str) -> Result<(), AdbError> {
+        let output = self.run_v2(&format!("pm uninstall {}", package_name))?;
+        let stdout = output.stdout_str();
+        if !output.success() || !stdout.contains("Success") {
+            return Err(AdbError::InstallError {
+                message: format!("{}{}", stdout, output.stderr_str())
+                    .trim()
+                    .to_string(),
+            });
         }
+        Ok(())
     }
 
-    pub fn list_packages(&self) -> Vec<String> {
-        let mut res: Vec<String> = Vec::new();
-        let out_put = self.run("pm list packages".to_string());
-        match out_put {
-            AdbConnectionOrString::String(packages_str) => {
-                let packages: Vec<&str> = packages_str.split("\n").collect();
-                for package in packages {
-                    res.push(package.to_string())
-                }
-                return res;
-            }
-            _ => res,
-        }
+    pub fn get_prop(&self, prop: &str) -> Result<String, AdbError> {
+        Ok(self.run("getprop ".to_string() + prop)?.trim().to_string())
+    }
+
+    pub fn list_packages(&self) -> Result<Vec<String>, AdbError> {
+        let out_put = self.run("pm list packages".to_string())?;
+        Ok(out_put
+            .split('\n')
+            .map(|package| package.to_string())
+            .collect())
     }
 
-    pub fn package_info(&self) {
-        unimplemented!()
+    pub fn package_info(&self) -> Result<(), AdbError> {
+        Err(not_implemented("package_info"))
     }
 
-    pub fn rotation(&self) {
-        unimplemented!()
+    pub fn rotation(&self) -> Result<(), AdbError> {
+        Err(not_implemented("rotation"))
     }
 
-    pub fn raw_window_sise(&self) {
-        unimplemented!()
+    pub fn raw_window_sise(&self) -> Result<(), AdbError> {
+        Err(not_implemented("raw_window_sise"))
     }
 
-    pub fn window_size(&self) {
-        unimplemented!()
+    pub fn window_size(&self) -> Result<(), AdbError> {
+        Err(not_implemented("window_size"))
     }
 
-    pub fn app_start(&self, package_name: &str, activity: &str) {
-        if activity != "" {
-            self.run(format!("am start -n {} / {}", package_name, activity));
+    pub fn app_start(&self, package_name: &str, activity: &str) -> Result<(), AdbError> {
+        if !activity.is_empty() {
+            self.run(format!("am start -n {}/{}", package_name, activity))?;
         } else {
             self.run(format!(
                 "monkey -p {} -c android.intent.category.LAUNCHER 1",
                 package_name
-            ));
+            ))?;
         }
+        Ok(())
     }
 
-    pub fn app_stop(&self, package_name: &str) {
-        self.run("am force-stop ".to_string() + package_name);
+    pub fn app_stop(&self, package_name: &str) -> Result<(), AdbError> {
+        self.run("am force-stop ".to_string() + package_name)
+            .map(|_| ())
     }
 
-    pub fn app_clear(&self, package_name: &str) {
-        self.run("pm clear ".to_string() + package_name);
+    pub fn app_clear(&self, package_name: &str) -> Result<(), AdbError> {
+        self.run("pm clear ".to_string() + package_name).map(|_| ())
     }
 
-    pub fn is_screen_on(&self) -> bool {
-        let res = self.run("dumpsys power".to_string());
-        return match res {
-            AdbConnectionOrString::String(str) => {
-                str.contains("mHoldingDisplaySuspendBlocker=true")
-            }
-            _ => false,
-        };
+    pub fn is_screen_on(&self) -> Result<bool, AdbError> {
+        let res = self.run("dumpsys power".to_string())?;
+        Ok(res.contains("mHoldingDisplaySuspendBlocker=true"))
     }
 
-    pub fn open_browser(&self, url: &str) {
-        self.run("am start -a android.intent.action.VIEW -d ".to_string() + url);
+    pub fn open_browser(&self, url: &str) -> Result<(), AdbError> {
+        self.run("am start -a android.intent.action.VIEW -d ".to_string() + url)
+            .map(|_| ())
     }
 
-    pub fn dump_hierarchy(&self) {
-        unimplemented!()
+    pub fn dump_hierarchy(&self) -> Result<(), AdbError> {
+        Err(not_implemented("dump_hierarchy"))
     }
 
-    pub fn curren_app(&self) {
-        unimplemented!()
+    pub fn curren_app(&self) -> Result<(), AdbError> {
+        Err(not_implemented("curren_app"))
     }
 
-    pub fn remove(&self, path: &str) {
-        self.run("rm ".to_string() + path);
+    pub fn remove(&self, path: &str) -> Result<(), AdbError> {
+        self.run("rm ".to_string() + path).map(|_| ())
     }
 
-    fn open_transport(&self, command: &str, time_out: time::Duration) -> AdbConnection {
-        let mut conn = self.client._connect();
-        conn.set_timeout(time_out).unwrap();
-        if command != "" {
-            if self.transport_id > 0 {
-                conn.send_command(&format!(
-                    "host-transport-id:{}:{}",
-                    self.transport_id, command
-                ))
-                .unwrap()
-            } else if self.serial != "" {
-                conn.send_command(&format!("host-serial:{}:{}", self.serial, command))
-                    .unwrap()
-            } else {
-                panic!("RuntimeError")
-            };
-            conn.check_oky();
+    pub(crate) fn shell(
+        &self,
+        cmd: &str,
+        stream: bool,
+        time_out: time::Duration,
+    ) -> Result<AdbConnectionOrString, AdbError> {
+        // a stream hands out the bytes as they come, that is only possible with `shell:`
+        if !stream && self.features()?.shell_v2() {
+            let output = self.shell_v2_with_timeout(cmd, time_out)?;
+            return Ok(AdbConnectionOrString::String(format!(
+                "{}{}",
+                output.stdout_str(),
+                output.stderr_str()
+            )));
+        }
+        let mut conn = self.open_transport("", time_out)?;
+        conn.send_command(&format!("shell:{}", cmd))?;
+        conn.check_oky()?;
+        if stream {
+            return Ok(AdbConnectionOrString::AdbConnection(conn));
+        }
+        Ok(AdbConnectionOrString::String(conn.read_until_close()?))
+    }
+
+    pub(crate) fn shell_v2(&self, cmd: &str) -> Result<ShellOutput, AdbError> {
+        self.shell_v2_with_timeout(cmd, self.client.socket_time)
+    }
+
+    fn shell_v2_with_timeout(
+        &self,
+        cmd: &str,
+        time_out: time::Duration,
+    ) -> Result<ShellOutput, AdbError> {
+        if !self.features()?.shell_v2() {
+            return self.shell_v1_exit_code(cmd, time_out);
+        }
+        let mut conn = self.open_transport("", time_out)?;
+        conn.send_command(&format!("shell,v2,raw:{}", cmd))?;
+        conn.check_oky()?;
+        // nothing is sent to stdin, commands reading it would wait forever otherwise
+        shell::write_packet(&mut conn, shell::ID_CLOSE_STDIN, &[])?;
+        let mut output = ShellOutput::default();
+        loop {
+            let (id, data) = shell::read_packet(&mut conn)?;
+            match id {
+                shell::ID_STDOUT => output.stdout.extend_from_slice(&data),
+                shell::ID_STDERR => output.stderr.extend_from_slice(&data),
+                shell::ID_EXIT => {
+                    output.exit_code = data.first().copied().unwrap_or(0) as i32;
+                    return Ok(output);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // appends the exit status to stdout and splits it off again
+    fn shell_v1_exit_code(
+        &self,
+        cmd: &str,
+        time_out: time::Duration,
+    ) -> Result<ShellOutput, AdbError> {
+        let mut conn = self.open_transport("", time_out)?;
+        conn.send_command(&format!("shell:{}; echo :$?", cmd))?;
+        conn.check_oky()?;
+        let out_put = conn.read_until_close()?;
+        let trimmed = out_put.strip_suffix('\n').unwrap_or(&out_put);
+        let trimmed = trimmed.strip_suffix('\r').unwrap_or(trimmed);
+        match trimmed.rsplit_once(':') {
+            Some((stdout, code)) => match code.parse::<i32>() {
+                Ok(exit_code) => Ok(ShellOutput {
+                    stdout: stdout.as_bytes().to_vec(),
+                    stderr: Vec::new(),
+                    exit_code,
+                }),
+                Err(error) => Err(AdbError::ParseResponseError {
+                    source: Box::new(error),
+                }),
+            },
+            None => Err(AdbError::ResponseStatusError {
+                content: format!("missing exit code in shell output: {}", out_put),
+            }),
+        }
+    }
+
+    /// How requests reach this device, the transport id wins over the serial.
+    pub fn selector(&self) -> TransportSelector {
+        if self.transport_id > 0 {
+            TransportSelector::TransportId(self.transport_id as u32)
+        } else if !self.serial.is_empty() {
+            TransportSelector::Serial(self.serial.clone())
+        } else {
+            TransportSelector::Any
+        }
+    }
+
+    fn open_transport(
+        &self,
+        command: &str,
+        time_out: time::Duration,
+    ) -> Result<AdbConnection, AdbError> {
+        let mut conn = self.client._connect()?;
+        conn.set_timeout(time_out)?;
+        let selector = self.selector();
+        if command.is_empty() {
+            conn.send_command(&selector.transport_request())?;
+        } else {
+            conn.send_command(&format!("{}:{}", selector.host_prefix(), command))?;
         }
-        conn
+        conn.check_oky()?;
+        Ok(conn)
     }
 }
 
@@ -228,68 +359,276 @@ pub struct AdbDevice {
     pub shell_mixin: ShellMixin,
 }
 
+// a device is shared between worker threads, the feature cache must not get in the way
+const _: () = {
+    fn assert_send_sync<T: Send + std::marker::Sync>() {}
+    let _ = assert_send_sync::<AdbDevice>;
+};
+
 impl AdbDevice {
-    pub fn get_with_command(&self, cmd: &str) -> String {
+    pub fn new(shell_mixin: ShellMixin) -> AdbDevice {
+        AdbDevice { shell_mixin }
+    }
+
+    pub fn get_with_command(&self, cmd: &str) -> Result<String, AdbError> {
         let mut conn = self
             .shell_mixin
-            .open_transport("", self.shell_mixin.client.socket_time);
-        conn.send_command(&format!("host-serial:{}:{}", self.shell_mixin.serial, cmd))
-            .unwrap();
-        conn.check_oky().unwrap();
-        conn.read_string_block().unwrap()
+            .open_transport(cmd, self.shell_mixin.client.socket_time)?;
+        conn.read_string_block()
     }
-    pub fn get_state(&self) -> String {
+
+    pub fn get_state(&self) -> Result<String, AdbError> {
         self.get_with_command("get-state")
     }
 
-    pub fn get_serial_no(&self) -> String {
+    pub fn get_serial_no(&self) -> Result<String, AdbError> {
         self.get_with_command("get-serialno")
     }
 
-    pub fn get_dev_path(&self) -> String {
+    pub fn get_dev_path(&self) -> Result<String, AdbError> {
         self.get_with_command("get-devpath")
     }
 
-    pub fn get_feature(&self) -> String {
+    pub fn get_feature(&self) -> Result<String, AdbError> {
         self.get_with_command("features")
     }
 
-    pub fn info(&self) -> HashMap<String, String> {
+    /// Parsed `get_feature`, queried once per device and used to pick the best protocol.
+    pub fn features(&self) -> Result<&FeatureSet, AdbError> {
+        self.shell_mixin.features()
+    }
+
+    pub fn info(&self) -> Result<HashMap<String, String>, AdbError> {
         let mut res: HashMap<String, String> = HashMap::new();
-        res.insert("serialno".to_string(), self.get_serial_no().to_string());
-        res.insert("devpath".to_string(), self.get_dev_path().to_string());
-        res.insert("state".to_string(), self.get_state().to_string());
-        res
+        res.insert("serialno".to_string(), self.get_serial_no()?);
+        res.insert("devpath".to_string(), self.get_dev_path()?);
+        res.insert("state".to_string(), self.get_state()?);
+        Ok(res)
     }
 
-    pub fn adb_out(&self, cmd: &str) -> String {
-        unimplemented!()
+    /// Run `cmd` through `exec:`, the output arrives byte for byte without a pty in between,
+    /// e.g. `screencap -p`.
+    pub fn adb_out(&self, cmd: &str) -> Result<Vec<u8>, AdbError> {
+        let mut conn = self.adb_out_stream(cmd)?;
+        conn.read_bytes_until_close()
     }
 
+    /// Like `adb_out` but returns the connection to read the output incrementally.
+    pub fn adb_out_stream(&self, cmd: &str) -> Result<AdbConnection, AdbError> {
+        let mut conn = self
+            .shell_mixin
+            .open_transport("", self.shell_mixin.client.socket_time)?;
+        conn.send_command(&format!("exec:{}", cmd))?;
+        conn.check_oky()?;
+        Ok(conn)
+    }
+
+    /// Run `cmd` and collect its output, or hand out the connection with `stream`. Collected
+    /// output goes through shell v2 when the device supports it, stderr follows stdout then.
     pub fn shell(
         &self,
         cmd: &str,
         stream: bool,
         time_out: time::Duration,
-    ) -> AdbConnectionOrString {
-        let mut conn = self.shell_mixin.open_transport("", time_out);
-        conn.send_command(&format!("shell:{}", cmd)).unwrap();
-        conn.check_oky().unwrap();
-        if stream {
-            return AdbConnectionOrString::AdbConnection(conn);
+    ) -> Result<AdbConnectionOrString, AdbError> {
+        self.shell_mixin.shell(cmd, stream, time_out)
+    }
+
+    /// Run `cmd` over the shell v2 protocol so stdout, stderr and the exit code stay apart.
+    /// Devices without `shell_v2` fall back to `shell:`, stderr is then merged into stdout.
+    pub fn shell_v2(&self, cmd: &str) -> Result<ShellOutput, AdbError> {
+        self.shell_mixin.shell_v2(cmd)
+    }
+
+    /// Start an interactive shell v2 session running `cmd`, an empty `cmd` opens a login shell.
+    /// With `pty` the remote side gets a terminal, needed by `su` and most REPLs. Reads block
+    /// without a timeout since the session may stay idle for long.
+    pub fn shell_session(&self, cmd: &str, pty: bool) -> Result<ShellSession, AdbError> {
+        if !self.features()?.shell_v2() {
+            return Err(AdbError::UnsupportedFeature {
+                feature: FeatureSet::SHELL_V2.to_string(),
+            });
         }
-        AdbConnectionOrString::String(conn.read_until_close().unwrap())
+        let mut conn = self.shell_mixin.open_transport("", time::Duration::ZERO)?;
+        let service = if pty {
+            format!("shell,v2,TERM=xterm-256color,pty:{}", cmd)
+        } else {
+            format!("shell,v2,raw:{}", cmd)
+        };
+        conn.send_command(&service)?;
+        conn.check_oky()?;
+        Ok(ShellSession::new(conn))
+    }
+
+    pub fn forward(
+        &self,
+        local: &ForwardSpec,
+        remote: &ForwardSpec,
+        norebind: bool,
+    ) -> Result<Option<u16>, AdbError> {
+        self.shell_mixin
+            .client
+            .forward(&self.shell_mixin.serial, local, remote, norebind)
+    }
+
+    pub fn forward_remove(&self, local: &ForwardSpec) -> Result<(), AdbError> {
+        self.shell_mixin
+            .client
+            .forward_remove(&self.shell_mixin.serial, local)
     }
 
-    pub fn shell_out_put(&self, cmd: &str) -> String {
-        let out_put = self
+    /// The forwards of this device.
+    pub fn forward_list(&self) -> Result<Vec<ForWardItem>, AdbError> {
+        Ok(self
             .shell_mixin
             .client
-            .shell(&self.shell_mixin.serial, cmd, false);
-        match out_put {
-            AdbConnectionOrString::String(str) => str,
-            _ => String::from("get shell_out_put error!"),
+            .forward_list()?
+            .into_iter()
+            .filter(|item| item.serial == self.shell_mixin.serial)
+            .collect())
+    }
+
+    /// Make `remote` on the device connect to `local` on the host. Returns the port adbd
+    /// allocated when `remote` is `tcp:0`.
+    pub fn reverse(
+        &self,
+        remote: &ForwardSpec,
+        local: &ForwardSpec,
+        norebind: bool,
+    ) -> Result<Option<u16>, AdbError> {
+        let rebind = if norebind { "norebind:" } else { "" };
+        let mut conn =
+            self.open_reverse(&format!("reverse:forward:{}{};{}", rebind, remote, local))?;
+        conn.check_oky()?;
+        read_allocated_port(&mut conn)
+    }
+
+    pub fn reverse_list(&self) -> Result<Vec<ReverseItem>, AdbError> {
+        let mut conn = self.open_reverse("reverse:list-forward")?;
+        let out_put = conn.read_string_block()?;
+        Ok(out_put
+            .lines()
+            .filter_map(|line| {
+                let parts: Vec<&str> = line.split_whitespace().collect();
+                match parts[..] {
+                    [_, remote, local] => Some(ReverseItem {
+                        remote: remote.to_string(),
+                        local: local.to_string(),
+                    }),
+                    _ => None,
+                }
+            })
+            .collect())
+    }
+
+    pub fn reverse_remove(&self, remote: &ForwardSpec) -> Result<(), AdbError> {
+        let mut conn = self.open_reverse(&format!("reverse:killforward:{}", remote))?;
+        conn.check_oky()
+    }
+
+    pub fn reverse_remove_all(&self) -> Result<(), AdbError> {
+        let mut conn = self.open_reverse("reverse:killforward-all")?;
+        conn.check_oky()
+    }
+
+    // the first OKAY opens the reverse service on the device, its own status follows
+    fn open_reverse(&self, service: &str) -> Result<AdbConnection, AdbError> {
+        let mut conn = self
+            .shell_mixin
+            .open_transport("", self.shell_mixin.client.socket_time)?;
+        conn.send_command(service)?;
+        conn.check_oky()?;
+        Ok(conn)
+    }
+
+    /// A sync session negotiating with the cached `features`.
+    pub fn sync(&self) -> Result<Sync, AdbError> {
+        self.features()?;
+        Sync::new(AdbDevice::new(self.shell_mixin.clone()))
+    }
+
+    /// Push a file or a whole directory tree.
+    pub fn push(&self, local: &Path, remote: &str) -> Result<usize, AdbError> {
+        if local.is_dir() {
+            return self.sync()?.push_dir(local, remote);
+        }
+        self.sync()?.push_file(local, remote)
+    }
+
+    /// Pull a file or a whole directory tree.
+    pub fn pull(&self, remote: &str, local: &Path) -> Result<usize, AdbError> {
+        let sync = self.sync()?;
+        let info = sync.stat(&format!("{}/", remote.trim_end_matches('/')))?;
+        if info.is_dir() {
+            return sync.pull_dir(remote, local);
+        }
+        sync.pull_file(remote, local)
+    }
+
+    /// Install a local apk. It is streamed through `abb_exec:` or `exec:cmd` when the device
+    /// supports it, older devices get it pushed to /data/local/tmp and installed with `pm`.
+    pub fn install(&self, apk: &Path) -> Result<(), AdbError> {
+        let features = self.features()?;
+        let output = if features.abb_exec() || features.cmd() {
+            let mut file = match File::open(apk) {
+                Ok(file) => file,
+                Err(error) => {
+                    return Err(AdbError::FileIoError {
+                        source: Box::new(error),
+                    })
+                }
+            };
+            let size = match file.metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(error) => {
+                    return Err(AdbError::FileIoError {
+                        source: Box::new(error),
+                    })
+                }
+            };
+            let service = if features.abb_exec() {
+                format!("abb_exec:package\0install\0-r\0-t\0-S\0{}", size)
+            } else {
+                format!("exec:cmd package install -r -t -S {}", size)
+            };
+            let mut conn = self
+                .shell_mixin
+                .open_transport("", self.shell_mixin.client.socket_time)?;
+            conn.send_command(&service)?;
+            conn.check_oky()?;
+            let mut buff = vec![0; 64 * 1024];
+            loop {
+                let n = match file.read(&mut buff) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(error) => {
+                        return Err(AdbError::FileIoError {
+                            source: Box::new(error),
+                        })
+                    }
+                };
+                conn.send_bytes(&buff[..n])?;
+            }
+            conn.read_until_close()?
+        } else {
+            let name = apk.file_name().unwrap_or_default().to_string_lossy();
+            let remote = format!("/data/local/tmp/{}", name);
+            self.sync()?.push_file(apk, &remote)?;
+            let output = self.shell_mixin.install_remote(&remote);
+            // clean up even when the install failed
+            self.shell_mixin.remove(&remote)?;
+            output?
+        };
+        if !output.contains("Success") {
+            return Err(AdbError::InstallError {
+                message: output.trim().to_string(),
+            });
         }
+        Ok(())
+    }
+
+    pub fn shell_out_put(&self, cmd: &str) -> Result<String, AdbError> {
+        self.shell_mixin.run(cmd.to_string())
     }
 }
 
@@ -299,7 +638,176 @@ impl Display for AdbDevice {
     }
 }
 
-struct Sync {
-    adb_client: AdbClient,
-    serial: String,
+#[cfg(test)]
+mod test {
+    use super::{AdbDevice, ShellMixin};
+    use crate::client::AdbClient;
+    use crate::error::AdbError;
+    use crate::proto::{
+        AdbConnectionOrString, ForwardSpec, ReverseItem, ShellOutput, TransportSelector,
+    };
+    use crate::testing::{MockDevice, MockServer};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time;
+
+    fn read_command(stream: &mut TcpStream) -> String {
+        let mut length = [0; 4];
+        stream.read_exact(&mut length).unwrap();
+        let length = usize::from_str_radix(std::str::from_utf8(&length).unwrap(), 16).unwrap();
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).unwrap();
+        String::from_utf8(body).unwrap()
+    }
+
+    #[test]
+    fn test_adb_out() {
+        let png = [0x89u8, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', 0, 0xff];
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_command(&mut stream), "host:transport:serial1");
+            stream.write_all(b"OKAY").unwrap();
+            let command = read_command(&mut stream);
+            stream.write_all(b"OKAY").unwrap();
+            stream.write_all(&png).unwrap();
+            command
+        });
+
+        let client = AdbClient::new(String::from("127.0.0.1"), port, time::Duration::new(10, 0));
+        let device = AdbDevice::new(ShellMixin::new(client, String::from("serial1"), 0, None));
+        assert_eq!(device.adb_out("screencap -p").unwrap(), png);
+        assert_eq!(server.join().unwrap(), "exec:screencap -p");
+    }
+
+    #[test]
+    fn test_reverse() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let server = thread::spawn(move || {
+            let mut commands = Vec::new();
+            for response in [
+                &b"OKAYOKAY"[..],
+                b"OKAYOKAY000541234",
+                b"OKAYOKAY0005port!",
+                b"OKAY0030(reverse) localabstract:mock tcp:8080\n(reverse)\n",
+            ] {
+                let (mut stream, _) = listener.accept().unwrap();
+                assert_eq!(read_command(&mut stream), "host:transport:serial1");
+                stream.write_all(b"OKAY").unwrap();
+                commands.push(read_command(&mut stream));
+                stream.write_all(response).unwrap();
+            }
+            commands
+        });
+
+        let client = AdbClient::new(String::from("127.0.0.1"), port, time::Duration::new(10, 0));
+        let device = AdbDevice::new(ShellMixin::new(client, String::from("serial1"), 0, None));
+        let remote = ForwardSpec::LocalAbstract(String::from("mock"));
+        let local = ForwardSpec::Tcp(8080);
+        assert_eq!(device.reverse(&remote, &local, false).unwrap(), None);
+        assert_eq!(
+            device.reverse(&ForwardSpec::Tcp(0), &local, true).unwrap(),
+            Some(41234)
+        );
+        assert!(matches!(
+            device.reverse(&ForwardSpec::Tcp(0), &local, true),
+            Err(AdbError::ParseResponseError { .. })
+        ));
+        assert_eq!(
+            device.reverse_list().unwrap(),
+            vec![ReverseItem {
+                remote: String::from("localabstract:mock"),
+                local: String::from("tcp:8080"),
+            }]
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                "reverse:forward:localabstract:mock;tcp:8080",
+                "reverse:forward:norebind:tcp:0;tcp:8080",
+                "reverse:forward:norebind:tcp:0;tcp:8080",
+                "reverse:list-forward"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shell_features() {
+        let server = MockServer::start().unwrap();
+        server.add_device(
+            MockDevice::new("emulator-5554")
+                .features("shell_v2,cmd")
+                .shell_output(
+                    "ls /data /sdcard",
+                    ShellOutput {
+                        stdout: b"/sdcard:\nDownload\n".to_vec(),
+                        stderr: b"ls: /data: Permission denied\n".to_vec(),
+                        exit_code: 1,
+                    },
+                ),
+        );
+        let device = server.client().device(TransportSelector::Any).unwrap();
+        let time_out = time::Duration::new(10, 0);
+        match device.shell("ls /data /sdcard", false, time_out).unwrap() {
+            AdbConnectionOrString::String(output) => {
+                assert_eq!(output, "/sdcard:\nDownload\nls: /data: Permission denied\n")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        device.shell("ls /data /sdcard", true, time_out).unwrap();
+        device.shell_mixin.run_v2("ls /data /sdcard").unwrap();
+
+        let requests = server.requests();
+        let shells = requests
+            .iter()
+            .filter(|request| request.starts_with("shell"))
+            .collect::<Vec<&String>>();
+        assert_eq!(
+            shells,
+            vec![
+                "shell,v2,raw:ls /data /sdcard",
+                "shell:ls /data /sdcard",
+                "shell,v2,raw:ls /data /sdcard"
+            ]
+        );
+        let features = requests
+            .iter()
+            .filter(|request| request.ends_with(":features"))
+            .count();
+        assert_eq!(features, 1);
+    }
+
+    #[test]
+    fn test_shell_mixin() {
+        let server = MockServer::start().unwrap();
+        server.add_device(
+            MockDevice::new("emulator-5554")
+                .shell("input text hello%sworld", "")
+                .shell(
+                    "pm list packages",
+                    "package:android\npackage:com.android.shell\n",
+                ),
+        );
+        let device = server.client().device(TransportSelector::Any).unwrap();
+        let shell = &device.shell_mixin;
+        // the text goes to `input text` as is, `%s` is its own escape for a space
+        shell.send_keys("hello%sworld").unwrap();
+        assert!(server
+            .requests()
+            .contains(&String::from("shell:input text hello%sworld")));
+        assert_eq!(
+            shell.list_packages().unwrap(),
+            vec!["package:android", "package:com.android.shell", ""]
+        );
+        match shell.rotation() {
+            Err(AdbError::ResponseStatusError { content }) => {
+                assert_eq!(content, "ShellMixin::rotation is not implemented")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(shell.install().is_err());
+    }
 }
@@ -1,12 +1,255 @@
 use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
 
 #[derive(Debug)]
 pub enum AdbError {
-    StartAdbFailed { source: Box<dyn Error> },
-    TcpConnectError { source: Box<dyn Error> },
-    TcpWriteError { source: Box<dyn Error> },
-    TcpReadError { source: Box<dyn Error> },
-    ParseResponseError { source: Box<dyn Error> },
-    ResponseStatusError { content: String },
-    UnknownError { source: Box<dyn Error> },
+    StartAdbFailed {
+        source: Box<dyn Error + Send + Sync + 'static>,
+    },
+    KillAdbFailed {
+        source: Box<dyn Error + Send + Sync + 'static>,
+    },
+    ServerVersionMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    TcpConnectError {
+        source: Box<dyn Error + Send + Sync + 'static>,
+    },
+    TcpWriteError {
+        source: Box<dyn Error + Send + Sync + 'static>,
+    },
+    TcpReadError {
+        source: Box<dyn Error + Send + Sync + 'static>,
+    },
+    ParseResponseError {
+        source: Box<dyn Error + Send + Sync + 'static>,
+    },
+    ResponseStatusError {
+        content: String,
+    },
+    FileIoError {
+        source: Box<dyn Error + Send + Sync + 'static>,
+    },
+    SyncError {
+        message: String,
+    },
+    InstallError {
+        message: String,
+    },
+    UnsupportedFeature {
+        feature: String,
+    },
+    KeyError {
+        source: Box<dyn Error + Send + Sync + 'static>,
+    },
+    TlsError {
+        source: Box<dyn Error + Send + Sync + 'static>,
+    },
+    PairingError {
+        message: String,
+    },
+    InvalidServerAddress {
+        address: String,
+    },
+    NoDevice,
+    MoreThanOneDevice,
+    DeviceNotFound {
+        serial: String,
+    },
+    DeviceOffline,
+    Unauthorized,
+    ClosedByPeer,
+    Timeout {
+        message: String,
+    },
+    UnknownError {
+        source: Box<dyn Error + Send + Sync + 'static>,
+    },
+}
+
+impl AdbError {
+    /// Map the payload of a FAIL response to the matching variant.
+    pub fn from_fail(message: String) -> AdbError {
+        let lower = message.to_lowercase();
+        if lower.starts_with("device unauthorized") {
+            AdbError::Unauthorized
+        } else if lower.starts_with("device offline") {
+            AdbError::DeviceOffline
+        } else if lower.starts_with("more than one") {
+            AdbError::MoreThanOneDevice
+        } else if lower.starts_with("no devices") || lower.starts_with("no emulators") {
+            AdbError::NoDevice
+        } else if lower.starts_with("device") && lower.contains("not found") {
+            // "device 'serial' not found" or just "device not found"
+            let serial = match (message.find('\''), message.rfind('\'')) {
+                (Some(start), Some(end)) if start < end => &message[start + 1..end],
+                _ => "",
+            };
+            AdbError::DeviceNotFound {
+                serial: serial.to_string(),
+            }
+        } else if lower == "closed" {
+            AdbError::ClosedByPeer
+        } else if lower.starts_with("timeout") || lower.starts_with("timed out") {
+            // only when the whole failure is the timeout, not e.g. "cannot connect to ...:
+            // Connection timed out"
+            AdbError::Timeout { message }
+        } else {
+            AdbError::ResponseStatusError { content: message }
+        }
+    }
+
+    /// Map a socket read failure, keeping EOF and timeouts apart from other errors.
+    pub(crate) fn from_read(error: io::Error) -> AdbError {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted => AdbError::ClosedByPeer,
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => AdbError::Timeout {
+                message: error.to_string(),
+            },
+            _ => AdbError::TcpReadError {
+                source: Box::new(error),
+            },
+        }
+    }
+
+    /// Same as `from_read` for socket writes.
+    pub(crate) fn from_write(error: io::Error) -> AdbError {
+        match error.kind() {
+            io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted => AdbError::ClosedByPeer,
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => AdbError::Timeout {
+                message: error.to_string(),
+            },
+            _ => AdbError::TcpWriteError {
+                source: Box::new(error),
+            },
+        }
+    }
+}
+
+impl Display for AdbError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdbError::StartAdbFailed { source } => {
+                write!(f, "failed to start adb server: {}", source)
+            }
+            AdbError::KillAdbFailed { source } => {
+                write!(f, "failed to kill adb server: {}", source)
+            }
+            AdbError::ServerVersionMismatch { expected, actual } => write!(
+                f,
+                "adb server version {} doesn't match expected version {}",
+                actual, expected
+            ),
+            AdbError::TcpConnectError { source } => write!(f, "connect error: {}", source),
+            AdbError::TcpWriteError { source } => write!(f, "write error: {}", source),
+            AdbError::TcpReadError { source } => write!(f, "read error: {}", source),
+            AdbError::ParseResponseError { source } => write!(f, "invalid response: {}", source),
+            AdbError::ResponseStatusError { content } => write!(f, "adb error: {}", content),
+            AdbError::FileIoError { source } => write!(f, "file error: {}", source),
+            AdbError::SyncError { message } => write!(f, "sync error: {}", message),
+            AdbError::InstallError { message } => write!(f, "install error: {}", message),
+            AdbError::UnsupportedFeature { feature } => {
+                write!(f, "device doesn't support {}", feature)
+            }
+            AdbError::KeyError { source } => write!(f, "adb key error: {}", source),
+            AdbError::TlsError { source } => write!(f, "tls error: {}", source),
+            AdbError::PairingError { message } => write!(f, "pairing failed: {}", message),
+            AdbError::InvalidServerAddress { address } => {
+                write!(f, "invalid adb server address: {}", address)
+            }
+            AdbError::NoDevice => write!(f, "no devices/emulators found"),
+            AdbError::MoreThanOneDevice => write!(f, "more than one device/emulator"),
+            AdbError::DeviceNotFound { serial } => write!(f, "device '{}' not found", serial),
+            AdbError::DeviceOffline => write!(f, "device offline"),
+            AdbError::Unauthorized => write!(f, "device unauthorized"),
+            AdbError::ClosedByPeer => write!(f, "connection closed by peer"),
+            AdbError::Timeout { message } => write!(f, "timed out: {}", message),
+            AdbError::UnknownError { source } => write!(f, "{}", source),
+        }
+    }
+}
+
+impl Error for AdbError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AdbError::StartAdbFailed { source }
+            | AdbError::KillAdbFailed { source }
+            | AdbError::TcpConnectError { source }
+            | AdbError::TcpWriteError { source }
+            | AdbError::TcpReadError { source }
+            | AdbError::ParseResponseError { source }
+            | AdbError::FileIoError { source }
+            | AdbError::KeyError { source }
+            | AdbError::TlsError { source }
+            | AdbError::UnknownError { source } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+// callers run devices on worker threads and send the errors back, keep that compiling
+const _: () = {
+    fn assert_send_sync<T: Send + Sync>() {}
+    let _ = assert_send_sync::<AdbError>;
+};
+
+#[cfg(test)]
+mod test {
+    use super::AdbError;
+
+    #[test]
+    fn test_from_fail() {
+        let error = AdbError::from_fail(String::from("device 'emulator-5554' not found"));
+        assert!(matches!(&error, AdbError::DeviceNotFound { serial } if serial == "emulator-5554"));
+        assert_eq!(error.to_string(), "device 'emulator-5554' not found");
+        assert!(matches!(
+            AdbError::from_fail(String::from(
+                "device unauthorized.\nThis adb server's $ADB_VENDOR_KEYS is not set"
+            )),
+            AdbError::Unauthorized
+        ));
+        assert!(matches!(
+            AdbError::from_fail(String::from("device offline")),
+            AdbError::DeviceOffline
+        ));
+        assert!(matches!(
+            AdbError::from_fail(String::from("more than one device/emulator")),
+            AdbError::MoreThanOneDevice
+        ));
+        assert!(matches!(
+            AdbError::from_fail(String::from("no devices/emulators found")),
+            AdbError::NoDevice
+        ));
+        assert!(matches!(
+            AdbError::from_fail(String::from("closed")),
+            AdbError::ClosedByPeer
+        ));
+        assert!(matches!(
+            AdbError::from_fail(String::from("cannot bind listener")),
+            AdbError::ResponseStatusError { .. }
+        ));
+        match AdbError::from_fail(String::from("timeout expired while waiting for device")) {
+            AdbError::Timeout { message } => {
+                assert_eq!(message, "timeout expired while waiting for device")
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        match AdbError::from_fail(String::from(
+            "cannot connect to 1.2.3.4:5555: Connection timed out",
+        )) {
+            AdbError::ResponseStatusError { content } => {
+                assert_eq!(
+                    content,
+                    "cannot connect to 1.2.3.4:5555: Connection timed out"
+                )
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
 }
@@ -1,8 +1,19 @@
 use std::net::TcpStream;
+pub mod adbd;
+pub mod auth;
 pub mod client;
 pub mod device;
 pub mod error;
+pub mod pairing;
 pub mod proto;
+pub mod server;
+pub mod shell;
+pub mod sync;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod tls;
+pub mod tracker;
+pub mod transport;
 
 // thx https://github.com/WangZemin0816/rust-adb/blob/d415ab988dce9090da987e066695803388b58ea4/src/adb_host/mod.rs
 pub trait SyncHostCommand {
@@ -22,4 +33,4 @@ pub struct SyncHostResponse {
 pub struct AsyncHostResponse {
     pub tcp_stream: TcpStream,
 }
-// thx end
\ No newline at end of file
+// thx end
@@ -0,0 +1,389 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time;
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes128Gcm, Key, Nonce};
+use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::Scalar;
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::auth::AdbKey;
+use crate::error::AdbError;
+use crate::tls;
+
+// https://android.googlesource.com/platform/packages/modules/adb/+/refs/heads/main/pairing_connection/
+const KEY_HEADER_VERSION: u8 = 1;
+const PACKET_HEADER_SIZE: usize = 6;
+const SPAKE2_MSG: u8 = 0;
+const PEER_INFO: u8 = 1;
+
+pub const PEER_INFO_SIZE: usize = 8192;
+// type byte of a PeerInfo, the client sends its key and the device its GUID
+pub const ADB_RSA_PUB_KEY: u8 = 0;
+pub const ADB_DEVICE_GUID: u8 = 0;
+// an encrypted PeerInfo plus the GCM tag is the largest packet
+const MAX_PACKET_SIZE: usize = 2 * PEER_INFO_SIZE;
+
+const EXPORTED_KEY_LABEL: &[u8] = b"adb-label\0";
+const EXPORTED_KEY_SIZE: usize = 64;
+const CLIENT_NAME: &[u8] = b"adb pair client\0";
+const SERVER_NAME: &[u8] = b"adb pair server\0";
+const HKDF_INFO: &[u8] = b"adb pairing_auth aes-128-gcm key";
+
+fn pairing_error(message: &str) -> AdbError {
+    AdbError::PairingError {
+        message: message.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Alice,
+    // the device, only played by the tests here
+    #[allow(dead_code)]
+    Bob,
+}
+
+impl Role {
+    // BoringSSL's M and N: the SHA-256 of the seed is a valid point encoding
+    fn point(seed: &[u8]) -> Result<EdwardsPoint, AdbError> {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&Sha256::digest(seed));
+        CompressedEdwardsY(hash)
+            .decompress()
+            .ok_or_else(|| pairing_error("SPAKE2 seed hash is not a curve point"))
+    }
+
+    // (own mask point, peer's mask point)
+    fn points(self) -> Result<(EdwardsPoint, EdwardsPoint), AdbError> {
+        let m = Role::point(b"edwards25519 point generation seed (M)")?;
+        let n = Role::point(b"edwards25519 point generation seed (N)")?;
+        Ok(match self {
+            Role::Alice => (m, n),
+            Role::Bob => (n, m),
+        })
+    }
+}
+
+/// SPAKE2 over edwards25519 as done by BoringSSL, which adb uses on both ends.
+struct Spake2 {
+    role: Role,
+    private_key: Scalar,
+    // the password scalar times the cofactor is what BoringSSL multiplies M and N with
+    password_scalar: Scalar,
+    password_hash: [u8; 64],
+    message: [u8; 32],
+}
+
+impl Spake2 {
+    fn new(role: Role, password: &[u8]) -> Result<Spake2, AdbError> {
+        let mut random = [0u8; 64];
+        rand::thread_rng().fill_bytes(&mut random);
+        let private_key = Scalar::from_bytes_mod_order_wide(&random);
+        let mut password_hash = [0u8; 64];
+        password_hash.copy_from_slice(&Sha512::digest(password));
+        let password_scalar =
+            Scalar::from_bytes_mod_order_wide(&password_hash) * Scalar::from(8u64).invert();
+        let (mask, _) = role.points()?;
+        let message = (ED25519_BASEPOINT_POINT * private_key + mask * password_scalar)
+            .mul_by_cofactor()
+            .compress()
+            .to_bytes();
+        Ok(Spake2 {
+            role,
+            private_key,
+            password_scalar,
+            password_hash,
+            message,
+        })
+    }
+
+    /// Derive the 64 byte shared key from the peer's message.
+    fn finish(&self, peer_message: &[u8]) -> Result<[u8; 64], AdbError> {
+        let peer_point = <[u8; 32]>::try_from(peer_message)
+            .ok()
+            .and_then(|bytes| CompressedEdwardsY(bytes).decompress())
+            .ok_or_else(|| pairing_error("invalid SPAKE2 message"))?;
+        let (_, peer_mask) = self.role.points()?;
+        let shared = ((peer_point - (peer_mask * self.password_scalar).mul_by_cofactor())
+            * self.private_key)
+            .mul_by_cofactor()
+            .compress()
+            .to_bytes();
+        let (alice_message, bob_message) = match self.role {
+            Role::Alice => (&self.message[..], peer_message),
+            Role::Bob => (peer_message, &self.message[..]),
+        };
+        let mut hasher = Sha512::new();
+        for field in [
+            CLIENT_NAME,
+            SERVER_NAME,
+            alice_message,
+            bob_message,
+            &shared[..],
+            &self.password_hash[..],
+        ] {
+            hasher.update((field.len() as u64).to_le_bytes());
+            hasher.update(field);
+        }
+        let mut key = [0u8; 64];
+        key.copy_from_slice(&hasher.finalize());
+        Ok(key)
+    }
+}
+
+/// AES-128-GCM keyed from the SPAKE2 key, the nonce is a per-direction message counter.
+struct PairingCipher {
+    cipher: Aes128Gcm,
+    encrypt_counter: u64,
+    decrypt_counter: u64,
+}
+
+impl PairingCipher {
+    fn new(key: &[u8]) -> Result<PairingCipher, AdbError> {
+        let mut aes_key = [0u8; 16];
+        Hkdf::<Sha256>::new(None, key)
+            .expand(HKDF_INFO, &mut aes_key)
+            .map_err(|_| pairing_error("invalid HKDF output length"))?;
+        Ok(PairingCipher {
+            cipher: Aes128Gcm::new(&Key::<Aes128Gcm>::from(aes_key)),
+            encrypt_counter: 0,
+            decrypt_counter: 0,
+        })
+    }
+
+    fn nonce(counter: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..8].copy_from_slice(&counter.to_le_bytes());
+        nonce
+    }
+
+    fn encrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, AdbError> {
+        let nonce = PairingCipher::nonce(self.encrypt_counter);
+        self.encrypt_counter += 1;
+        self.cipher
+            .encrypt(Nonce::from_slice(&nonce), data)
+            .map_err(|_| pairing_error("encryption failed"))
+    }
+
+    fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, AdbError> {
+        let nonce = PairingCipher::nonce(self.decrypt_counter);
+        self.decrypt_counter += 1;
+        self.cipher
+            .decrypt(Nonce::from_slice(&nonce), data)
+            .map_err(|_| pairing_error("wrong pairing code"))
+    }
+}
+
+fn write_packet(conn: &mut impl Write, kind: u8, payload: &[u8]) -> Result<(), AdbError> {
+    let mut packet = Vec::with_capacity(PACKET_HEADER_SIZE + payload.len());
+    packet.push(KEY_HEADER_VERSION);
+    packet.push(kind);
+    packet.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    packet.extend_from_slice(payload);
+    conn.write_all(&packet).map_err(AdbError::from_write)?;
+    conn.flush().map_err(AdbError::from_write)
+}
+
+fn read_packet(conn: &mut impl Read, kind: u8) -> Result<Vec<u8>, AdbError> {
+    let mut header = [0u8; PACKET_HEADER_SIZE];
+    conn.read_exact(&mut header).map_err(AdbError::from_read)?;
+    if header[0] != KEY_HEADER_VERSION || header[1] != kind {
+        return Err(pairing_error(&format!(
+            "unexpected packet version {} type {}",
+            header[0], header[1]
+        )));
+    }
+    let length = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+    if length == 0 || length > MAX_PACKET_SIZE {
+        return Err(pairing_error(&format!("invalid packet size {}", length)));
+    }
+    let mut payload = vec![0u8; length];
+    conn.read_exact(&mut payload).map_err(AdbError::from_read)?;
+    Ok(payload)
+}
+
+/// A PeerInfo is a type byte followed by zero padded data, 8192 bytes in total.
+fn encode_peer_info(kind: u8, data: &str) -> Result<Vec<u8>, AdbError> {
+    if data.len() >= PEER_INFO_SIZE {
+        return Err(pairing_error("peer info too long"));
+    }
+    let mut info = vec![0u8; PEER_INFO_SIZE];
+    info[0] = kind;
+    info[1..1 + data.len()].copy_from_slice(data.as_bytes());
+    Ok(info)
+}
+
+fn decode_peer_info(info: &[u8]) -> Result<(u8, String), AdbError> {
+    if info.len() != PEER_INFO_SIZE {
+        return Err(pairing_error("invalid peer info size"));
+    }
+    let data = &info[1..];
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    Ok((info[0], String::from_utf8_lossy(&data[..end]).to_string()))
+}
+
+// swap SPAKE2 messages, then the encrypted PeerInfos; returns the peer's
+fn exchange<S: Read + Write>(
+    conn: &mut S,
+    role: Role,
+    password: &[u8],
+    peer_info: &[u8],
+) -> Result<(u8, String), AdbError> {
+    let spake2 = Spake2::new(role, password)?;
+    write_packet(conn, SPAKE2_MSG, &spake2.message)?;
+    let key = spake2.finish(&read_packet(conn, SPAKE2_MSG)?)?;
+
+    let mut cipher = PairingCipher::new(&key)?;
+    write_packet(conn, PEER_INFO, &cipher.encrypt(peer_info)?)?;
+    // the device hangs up when it can't decrypt our PeerInfo
+    let packet = match read_packet(conn, PEER_INFO) {
+        Err(AdbError::ClosedByPeer) => return Err(pairing_error("wrong pairing code")),
+        result => result?,
+    };
+    decode_peer_info(&cipher.decrypt(&packet)?)
+}
+
+/// Pair with a device in wireless debugging without an adb server. `addr` and `code` are the
+/// ones shown in the "Pair device with pairing code" dialog; on success the device trusts
+/// `key` and its GUID is returned. A zero `time_out` blocks forever.
+pub fn pair(
+    addr: &str,
+    code: &str,
+    key: &AdbKey,
+    time_out: time::Duration,
+) -> Result<String, AdbError> {
+    let conn = TcpStream::connect(addr).map_err(|error| AdbError::TcpConnectError {
+        source: Box::new(error),
+    })?;
+    if !time_out.is_zero() {
+        for result in [
+            conn.set_read_timeout(Some(time_out)),
+            conn.set_write_timeout(Some(time_out)),
+        ] {
+            result.map_err(|error| AdbError::TcpConnectError {
+                source: Box::new(error),
+            })?;
+        }
+    }
+    let host = conn
+        .peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default();
+    let mut conn = tls::connect(key, &host, conn)?;
+
+    // binding the password to this TLS session keeps a man in the middle out
+    let exported = conn
+        .conn
+        .export_keying_material([0u8; EXPORTED_KEY_SIZE], EXPORTED_KEY_LABEL, None)
+        .map_err(|error| AdbError::TlsError {
+            source: Box::new(error),
+        })?;
+    let mut password = code.as_bytes().to_vec();
+    password.extend_from_slice(&exported);
+
+    let peer_info = encode_peer_info(ADB_RSA_PUB_KEY, &key.public_key_string())?;
+    match exchange(&mut conn, Role::Alice, &password, &peer_info)? {
+        (ADB_DEVICE_GUID, guid) => Ok(guid),
+        (kind, _) => Err(AdbError::PairingError {
+            message: format!("unexpected peer info type {}", kind),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::test::TEST_KEY;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // the device side: accept one pairing attempt and return the client's PeerInfo
+    fn serve(code: &'static str, kind: u8) -> (String, thread::JoinHandle<Result<String, String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let mut conn = tls::test::accept(sock);
+            let exported = conn
+                .conn
+                .export_keying_material([0u8; EXPORTED_KEY_SIZE], EXPORTED_KEY_LABEL, None)
+                .unwrap();
+            let mut password = code.as_bytes().to_vec();
+            password.extend_from_slice(&exported);
+            let peer_info = encode_peer_info(kind, "adb-R58M-guid").unwrap();
+            exchange(&mut conn, Role::Bob, &password, &peer_info)
+                .map(|(kind, data)| {
+                    assert_eq!(kind, ADB_RSA_PUB_KEY);
+                    data
+                })
+                .map_err(|error| error.to_string())
+        });
+        (addr, server)
+    }
+
+    #[test]
+    fn test_spake2() {
+        let alice = Spake2::new(Role::Alice, b"123456").unwrap();
+        let bob = Spake2::new(Role::Bob, b"123456").unwrap();
+        let key = alice.finish(&bob.message).unwrap();
+        assert_eq!(key, bob.finish(&alice.message).unwrap());
+
+        let eve = Spake2::new(Role::Bob, b"654321").unwrap();
+        assert_ne!(
+            alice.finish(&eve.message).unwrap(),
+            eve.finish(&alice.message).unwrap()
+        );
+        assert!(alice.finish(&[0xff; 31]).is_err());
+    }
+
+    #[test]
+    fn test_peer_info() {
+        let info = encode_peer_info(ADB_RSA_PUB_KEY, "QAAAAA== user@host").unwrap();
+        assert_eq!(info.len(), PEER_INFO_SIZE);
+        assert_eq!(
+            decode_peer_info(&info).unwrap(),
+            (ADB_RSA_PUB_KEY, String::from("QAAAAA== user@host"))
+        );
+        assert!(encode_peer_info(ADB_RSA_PUB_KEY, &"a".repeat(PEER_INFO_SIZE)).is_err());
+    }
+
+    #[test]
+    fn test_pair() {
+        let key = AdbKey::from_pem(TEST_KEY)
+            .unwrap()
+            .with_comment("user@host");
+        let (addr, server) = serve("123456", ADB_DEVICE_GUID);
+        let guid = pair(&addr, "123456", &key, time::Duration::from_secs(10)).unwrap();
+        assert_eq!(guid, "adb-R58M-guid");
+        assert_eq!(server.join().unwrap().unwrap(), key.public_key_string());
+    }
+
+    #[test]
+    fn test_pair_wrong_code() {
+        let key = AdbKey::from_pem(TEST_KEY).unwrap();
+        let (addr, server) = serve("123456", ADB_DEVICE_GUID);
+        match pair(&addr, "654321", &key, time::Duration::from_secs(10)) {
+            Err(AdbError::PairingError { message }) => assert_eq!(message, "wrong pairing code"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(server.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_pair_peer_info_type() {
+        let key = AdbKey::from_pem(TEST_KEY).unwrap();
+        let (addr, server) = serve("123456", 1);
+        match pair(&addr, "123456", &key, time::Duration::from_secs(10)) {
+            Err(AdbError::PairingError { message }) => {
+                assert_eq!(message, "unexpected peer info type 1")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        server.join().unwrap().unwrap();
+    }
+}
@@ -1,4 +1,6 @@
 use crate::client::AdbConnection;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
 
 #[derive(Debug)]
 pub enum AdbConnectionOrString {
@@ -6,19 +8,416 @@ pub enum AdbConnectionOrString {
     String(String),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceEventKind {
+    Connected,
+    Disconnected,
+    StateChanged,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct DeviceEvent {
     pub present: bool,
     pub serial: String,
     pub status: String,
+    pub kind: DeviceEventKind,
 }
 
+/// Connection state of a device as reported by `host:devices-l`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceState {
+    Device,
+    Offline,
+    Unauthorized,
+    Authorizing,
+    Connecting,
+    Recovery,
+    Rescue,
+    Sideload,
+    Bootloader,
+    Host,
+    NoPermissions,
+    Unknown(String),
+}
+
+impl DeviceState {
+    pub fn parse(state: &str) -> DeviceState {
+        match state {
+            "device" => DeviceState::Device,
+            "offline" => DeviceState::Offline,
+            "unauthorized" => DeviceState::Unauthorized,
+            "authorizing" => DeviceState::Authorizing,
+            "connecting" => DeviceState::Connecting,
+            "recovery" => DeviceState::Recovery,
+            "rescue" => DeviceState::Rescue,
+            "sideload" => DeviceState::Sideload,
+            "bootloader" => DeviceState::Bootloader,
+            "host" => DeviceState::Host,
+            "no permissions" => DeviceState::NoPermissions,
+            other => DeviceState::Unknown(other.to_string()),
+        }
+    }
+}
+
+impl Display for DeviceState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceState::Device => write!(f, "device"),
+            DeviceState::Offline => write!(f, "offline"),
+            DeviceState::Unauthorized => write!(f, "unauthorized"),
+            DeviceState::Authorizing => write!(f, "authorizing"),
+            DeviceState::Connecting => write!(f, "connecting"),
+            DeviceState::Recovery => write!(f, "recovery"),
+            DeviceState::Rescue => write!(f, "rescue"),
+            DeviceState::Sideload => write!(f, "sideload"),
+            DeviceState::Bootloader => write!(f, "bootloader"),
+            DeviceState::Host => write!(f, "host"),
+            DeviceState::NoPermissions => write!(f, "no permissions"),
+            DeviceState::Unknown(state) => write!(f, "{}", state),
+        }
+    }
+}
+
+/// One line of `host:devices-l`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    pub serial: String,
+    pub state: DeviceState,
+    pub product: Option<String>,
+    pub model: Option<String>,
+    pub device: Option<String>,
+    pub transport_id: Option<u32>,
+    pub usb: Option<String>,
+}
+
+impl DeviceInfo {
+    /// Parse "serial  state key:value ...", `None` for blank lines.
+    pub fn parse(line: &str) -> Option<DeviceInfo> {
+        let mut parts = line.split_whitespace();
+        let serial = parts.next()?;
+        let state = parts.next()?;
+        let mut info = DeviceInfo {
+            serial: serial.to_string(),
+            state: DeviceState::parse(state),
+            product: None,
+            model: None,
+            device: None,
+            transport_id: None,
+            usb: None,
+        };
+        // "no permissions (reason); see [url]" spans several words
+        if state == "no" {
+            info.state = DeviceState::NoPermissions;
+        }
+        for part in parts {
+            match part.split_once(':') {
+                Some(("product", value)) => info.product = Some(value.to_string()),
+                Some(("model", value)) => info.model = Some(value.to_string()),
+                Some(("device", value)) => info.device = Some(value.to_string()),
+                Some(("transport_id", value)) => info.transport_id = value.parse().ok(),
+                Some(("usb", value)) => info.usb = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Some(info)
+    }
+}
+
+/// Which device a request is routed to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransportSelector {
+    Serial(String),
+    TransportId(u32),
+    /// The only connected device.
+    Any,
+    /// The only device connected over USB.
+    Usb,
+    /// The only emulator or TCP/IP device.
+    Local,
+}
+
+impl TransportSelector {
+    /// `Serial` from `ANDROID_SERIAL` when it is set, `Any` otherwise.
+    pub fn from_env() -> TransportSelector {
+        TransportSelector::from_serial(std::env::var("ANDROID_SERIAL").ok())
+    }
+
+    fn from_serial(serial: Option<String>) -> TransportSelector {
+        match serial {
+            Some(serial) if !serial.is_empty() => TransportSelector::Serial(serial),
+            _ => TransportSelector::Any,
+        }
+    }
+
+    /// The request switching a server connection over to the device.
+    pub fn transport_request(&self) -> String {
+        match self {
+            TransportSelector::Serial(serial) => format!("host:transport:{}", serial),
+            TransportSelector::TransportId(id) => format!("host:transport-id:{}", id),
+            TransportSelector::Any => String::from("host:transport-any"),
+            TransportSelector::Usb => String::from("host:transport-usb"),
+            TransportSelector::Local => String::from("host:transport-local"),
+        }
+    }
+
+    /// The prefix of host requests about the device, e.g. `<prefix>:features`.
+    pub fn host_prefix(&self) -> String {
+        match self {
+            TransportSelector::Serial(serial) => format!("host-serial:{}", serial),
+            TransportSelector::TransportId(id) => format!("host-transport-id:{}", id),
+            TransportSelector::Any => String::from("host"),
+            TransportSelector::Usb => String::from("host-usb"),
+            TransportSelector::Local => String::from("host-local"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ForWardItem {
     pub serial: String,
     pub local: String,
     pub remote: String,
 }
 
+/// One side of a forward or reverse forward.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ForwardSpec {
+    /// `Tcp(0)` lets the server pick a free port.
+    Tcp(u16),
+    LocalAbstract(String),
+    LocalReserved(String),
+    LocalFilesystem(String),
+    Jdwp(u32),
+    Dev(String),
+}
+
+impl Display for ForwardSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ForwardSpec::Tcp(port) => write!(f, "tcp:{}", port),
+            ForwardSpec::LocalAbstract(name) => write!(f, "localabstract:{}", name),
+            ForwardSpec::LocalReserved(name) => write!(f, "localreserved:{}", name),
+            ForwardSpec::LocalFilesystem(path) => write!(f, "localfilesystem:{}", path),
+            ForwardSpec::Jdwp(pid) => write!(f, "jdwp:{}", pid),
+            ForwardSpec::Dev(path) => write!(f, "dev:{}", path),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ReverseItem {
     pub remote: String,
     pub local: String,
 }
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileInfo {
+    pub path: String,
+    pub mode: u32,
+    pub size: u64,
+    pub mtime: i64,
+    // only reported by the v2 sync requests (STA2/LST2/LIS2)
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub inode: Option<u64>,
+    pub nlink: Option<u32>,
+}
+
+impl FileInfo {
+    const S_IFMT: u32 = 0o170000;
+
+    /// STAT v1 reports an all zero entry for a missing path.
+    pub fn exists(&self) -> bool {
+        self.mode != 0
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & Self::S_IFMT == 0o040000
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & Self::S_IFMT == 0o100000
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & Self::S_IFMT == 0o120000
+    }
+}
+
+/// The features advertised by `host-serial:<serial>:features`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeatureSet {
+    features: HashSet<String>,
+}
+
+impl FeatureSet {
+    pub const SHELL_V2: &'static str = "shell_v2";
+    pub const CMD: &'static str = "cmd";
+    pub const STAT_V2: &'static str = "stat_v2";
+    pub const LS_V2: &'static str = "ls_v2";
+    pub const ABB: &'static str = "abb";
+    pub const ABB_EXEC: &'static str = "abb_exec";
+    pub const SENDRECV_V2: &'static str = "sendrecv_v2";
+    pub const SENDRECV_V2_BROTLI: &'static str = "sendrecv_v2_brotli";
+    pub const SENDRECV_V2_LZ4: &'static str = "sendrecv_v2_lz4";
+    pub const SENDRECV_V2_ZSTD: &'static str = "sendrecv_v2_zstd";
+    pub const TRACK_APP: &'static str = "track_app";
+    pub const FIXED_PUSH_MKDIR: &'static str = "fixed_push_mkdir";
+    pub const FIXED_PUSH_SYMLINK_TIMESTAMP: &'static str = "fixed_push_symlink_timestamp";
+
+    pub fn parse(raw: &str) -> FeatureSet {
+        FeatureSet {
+            features: raw
+                .split(',')
+                .map(|feature| feature.trim())
+                .filter(|feature| !feature.is_empty())
+                .map(|feature| feature.to_string())
+                .collect(),
+        }
+    }
+
+    pub fn contains(&self, feature: &str) -> bool {
+        self.features.contains(feature)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.features.iter().map(|feature| feature.as_str())
+    }
+
+    pub fn shell_v2(&self) -> bool {
+        self.contains(Self::SHELL_V2)
+    }
+
+    pub fn cmd(&self) -> bool {
+        self.contains(Self::CMD)
+    }
+
+    pub fn stat_v2(&self) -> bool {
+        self.contains(Self::STAT_V2)
+    }
+
+    pub fn ls_v2(&self) -> bool {
+        self.contains(Self::LS_V2)
+    }
+
+    pub fn abb(&self) -> bool {
+        self.contains(Self::ABB)
+    }
+
+    pub fn abb_exec(&self) -> bool {
+        self.contains(Self::ABB_EXEC)
+    }
+
+    pub fn sendrecv_v2(&self) -> bool {
+        self.contains(Self::SENDRECV_V2)
+    }
+
+    pub fn track_app(&self) -> bool {
+        self.contains(Self::TRACK_APP)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShellOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit_code: i32,
+}
+
+impl ShellOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == 0
+    }
+
+    pub fn stdout_str(&self) -> String {
+        String::from_utf8_lossy(&self.stdout).to_string()
+    }
+
+    pub fn stderr_str(&self) -> String {
+        String::from_utf8_lossy(&self.stderr).to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WaitTransport {
+    Any,
+    Usb,
+    Local,
+}
+
+impl Display for WaitTransport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WaitTransport::Any => write!(f, "any"),
+            WaitTransport::Usb => write!(f, "usb"),
+            WaitTransport::Local => write!(f, "local"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WaitState {
+    Device,
+    Recovery,
+    Rescue,
+    Sideload,
+    Bootloader,
+    Disconnect,
+}
+
+impl Display for WaitState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WaitState::Device => write!(f, "device"),
+            WaitState::Recovery => write!(f, "recovery"),
+            WaitState::Rescue => write!(f, "rescue"),
+            WaitState::Sideload => write!(f, "sideload"),
+            WaitState::Bootloader => write!(f, "bootloader"),
+            WaitState::Disconnect => write!(f, "disconnect"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FeatureSet, TransportSelector};
+
+    #[test]
+    fn test_feature_set_parse() {
+        let features = FeatureSet::parse(
+            "shell_v2,cmd, stat_v2,ls_v2,,fixed_push_mkdir,abb_exec,sendrecv_v2\n",
+        );
+        assert!(features.shell_v2());
+        assert!(features.cmd());
+        assert!(features.stat_v2());
+        assert!(features.ls_v2());
+        assert!(features.abb_exec());
+        assert!(!features.abb());
+        assert!(features.sendrecv_v2());
+        assert!(!features.contains(FeatureSet::SENDRECV_V2_ZSTD));
+        assert!(features.contains(FeatureSet::FIXED_PUSH_MKDIR));
+        assert!(!features.contains(""));
+        assert_eq!(features.iter().count(), 7);
+    }
+
+    #[test]
+    fn test_selector_from_serial() {
+        assert_eq!(
+            TransportSelector::from_serial(Some(String::from("emulator-5554"))),
+            TransportSelector::Serial(String::from("emulator-5554"))
+        );
+        assert_eq!(
+            TransportSelector::from_serial(Some(String::new())),
+            TransportSelector::Any
+        );
+        assert_eq!(TransportSelector::from_serial(None), TransportSelector::Any);
+    }
+
+    #[test]
+    fn test_feature_set_parse_empty() {
+        let features = FeatureSet::parse("");
+        assert_eq!(features.iter().count(), 0);
+        assert!(!features.shell_v2());
+        assert!(!features.track_app());
+    }
+}
@@ -0,0 +1,229 @@
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+use std::time;
+
+use crate::client::{adb_path, is_not_listening, AdbClient, AdbConnection};
+use crate::error::AdbError;
+use crate::transport::ServerAddress;
+
+const DEFAULT_DEADLINE: time::Duration = time::Duration::from_secs(10);
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// Lifecycle of the adb server an `AdbClient` talks to.
+#[derive(Debug, Clone)]
+pub struct AdbServer {
+    client: AdbClient,
+    adb_path: Option<PathBuf>,
+}
+
+impl AdbServer {
+    pub fn new(client: AdbClient) -> AdbServer {
+        AdbServer {
+            client,
+            adb_path: None,
+        }
+    }
+
+    /// Use this adb binary for `start-server` instead of the downloaded one.
+    pub fn with_adb_path(mut self, adb_path: impl Into<PathBuf>) -> AdbServer {
+        self.adb_path = Some(adb_path.into());
+        self
+    }
+
+    /// Version of the running server, `None` when nothing listens on the port.
+    pub fn probe(&self) -> Result<Option<u32>, AdbError> {
+        let mut conn = match self.try_connect()? {
+            Some(conn) => conn,
+            None => return Ok(None),
+        };
+        conn.set_timeout(self.client.socket_time)?;
+        conn.send_command("host:version")?;
+        conn.check_oky()?;
+        parse_version(&conn.read_string_block()?).map(Some)
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(self.probe(), Ok(Some(_)))
+    }
+
+    /// Run `adb start-server` and wait until the server answers, returns its version.
+    pub fn start(&self) -> Result<u32, AdbError> {
+        let adb = match &self.adb_path {
+            Some(path) => path.clone(),
+            None => PathBuf::from(adb_path()?),
+        };
+        let mut command = Command::new(&adb);
+        match self.client.address() {
+            ServerAddress::Tcp { port, .. } => command.arg("-P").arg(port.to_string()),
+            address => command.arg("-L").arg(address.to_string()),
+        };
+        let output =
+            command
+                .arg("start-server")
+                .output()
+                .map_err(|error| AdbError::StartAdbFailed {
+                    source: Box::new(error),
+                })?;
+        if !output.status.success() {
+            return Err(AdbError::StartAdbFailed {
+                source: String::from_utf8_lossy(&output.stderr).trim().into(),
+            });
+        }
+        log::info!(
+            "start-server done! {}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+        let deadline = time::Instant::now() + self.deadline();
+        loop {
+            if let Ok(Some(version)) = self.probe() {
+                return Ok(version);
+            }
+            if time::Instant::now() >= deadline {
+                return Err(AdbError::StartAdbFailed {
+                    source: "server did not come up after start-server".into(),
+                });
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Send `host:kill` and wait until the port is released, a stopped server is not an error.
+    pub fn kill(&self) -> Result<(), AdbError> {
+        let mut conn = match self.try_connect()? {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+        conn.set_timeout(self.client.socket_time)?;
+        conn.send_command("host:kill")?;
+        conn.check_oky()?;
+        drop(conn);
+        let deadline = time::Instant::now() + self.deadline();
+        while self.try_connect()?.is_some() {
+            if time::Instant::now() >= deadline {
+                return Err(AdbError::KillAdbFailed {
+                    source: "server still running after host:kill".into(),
+                });
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    pub fn restart(&self) -> Result<u32, AdbError> {
+        self.kill()?;
+        self.start()
+    }
+
+    /// Make sure a server of version `expected` is running, starting or restarting it as needed.
+    pub fn ensure_version(&self, expected: u32) -> Result<u32, AdbError> {
+        let actual = match self.probe()? {
+            Some(version) if version == expected => return Ok(version),
+            Some(version) => {
+                log::info!(
+                    "adb server version {} doesn't match {}, restarting",
+                    version,
+                    expected
+                );
+                self.restart()?
+            }
+            None => self.start()?,
+        };
+        if actual != expected {
+            return Err(AdbError::ServerVersionMismatch { expected, actual });
+        }
+        Ok(actual)
+    }
+
+    fn try_connect(&self) -> Result<Option<AdbConnection>, AdbError> {
+        match self.client.address().connect() {
+            Ok(transport) => Ok(Some(AdbConnection::new(transport))),
+            Err(error) if is_not_listening(&error) => Ok(None),
+            Err(error) => Err(AdbError::TcpConnectError {
+                source: Box::new(error),
+            }),
+        }
+    }
+
+    fn deadline(&self) -> time::Duration {
+        if self.client.socket_time.is_zero() {
+            DEFAULT_DEADLINE
+        } else {
+            self.client.socket_time
+        }
+    }
+}
+
+/// `host:version` answers with the version as 4 hex digits.
+pub(crate) fn parse_version(content: &str) -> Result<u32, AdbError> {
+    u32::from_str_radix(content, 16).map_err(|error| AdbError::ParseResponseError {
+        source: Box::new(error),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::AdbServer;
+    use crate::client::AdbClient;
+    use crate::error::AdbError;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::time;
+
+    fn client(port: u16) -> AdbClient {
+        AdbClient::new(
+            String::from("127.0.0.1"),
+            port as u32,
+            time::Duration::from_secs(2),
+        )
+    }
+
+    fn unused_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn read_command(stream: &mut impl Read) -> String {
+        let mut length = [0; 4];
+        stream.read_exact(&mut length).unwrap();
+        let length = usize::from_str_radix(std::str::from_utf8(&length).unwrap(), 16).unwrap();
+        let mut command = vec![0; length];
+        stream.read_exact(&mut command).unwrap();
+        String::from_utf8(command).unwrap()
+    }
+
+    #[test]
+    fn test_probe_and_kill() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_command(&mut stream), "host:version");
+            stream.write_all(b"OKAY00040029").unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_command(&mut stream), "host:kill");
+            stream.write_all(b"OKAY").unwrap();
+        });
+
+        let server_manager = AdbServer::new(client(port));
+        assert_eq!(server_manager.probe().unwrap(), Some(41));
+        server_manager.kill().unwrap();
+        server.join().unwrap();
+        assert_eq!(server_manager.probe().unwrap(), None);
+        // killing a stopped server is fine
+        server_manager.kill().unwrap();
+    }
+
+    #[test]
+    fn test_start_failed() {
+        let server_manager =
+            AdbServer::new(client(unused_port())).with_adb_path("/nonexistent/adb");
+        match server_manager.ensure_version(41) {
+            Err(AdbError::StartAdbFailed { .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use crate::client::AdbConnection;
+use crate::error::AdbError;
+
+// https://android.googlesource.com/platform/packages/modules/adb/+/refs/heads/main/shell_protocol.h
+pub const ID_STDIN: u8 = 0;
+pub const ID_STDOUT: u8 = 1;
+pub const ID_STDERR: u8 = 2;
+pub const ID_EXIT: u8 = 3;
+pub const ID_CLOSE_STDIN: u8 = 4;
+pub const ID_WINDOW_SIZE_CHANGE: u8 = 5;
+
+/// Every shell v2 packet is a 1 byte id and a little endian u32 length followed by the data.
+pub fn write_packet(conn: &mut AdbConnection, id: u8, data: &[u8]) -> Result<(), AdbError> {
+    let mut packet = Vec::with_capacity(5 + data.len());
+    packet.push(id);
+    packet.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    packet.extend_from_slice(data);
+    conn.send_bytes(&packet)
+}
+
+pub fn read_packet(conn: &mut AdbConnection) -> Result<(u8, Vec<u8>), AdbError> {
+    let header = conn.read_bytes(5)?;
+    let length = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+    let data = conn.read_bytes(length as usize)?;
+    Ok((header[0], data))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShellEvent {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    Exit(i32),
+}
+
+/// An interactive shell v2 session, created by `AdbDevice::shell_session`.
+#[derive(Debug)]
+pub struct ShellSession {
+    conn: AdbConnection,
+    exit_code: Option<i32>,
+}
+
+impl ShellSession {
+    // adbd buffers a single packet, keep stdin writes well below its buffer size
+    const STDIN_CHUNK: usize = 16 * 1024;
+
+    pub(crate) fn new(conn: AdbConnection) -> ShellSession {
+        ShellSession {
+            conn,
+            exit_code: None,
+        }
+    }
+
+    pub fn write_stdin(&mut self, data: &[u8]) -> Result<(), AdbError> {
+        for chunk in data.chunks(Self::STDIN_CHUNK) {
+            write_packet(&mut self.conn, ID_STDIN, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Signal EOF on the remote stdin, e.g. to end `sqlite3` reading a script.
+    pub fn close_stdin(&mut self) -> Result<(), AdbError> {
+        write_packet(&mut self.conn, ID_CLOSE_STDIN, &[])
+    }
+
+    /// Resize the remote pty, the pixel sizes may be 0.
+    pub fn resize(
+        &mut self,
+        rows: u16,
+        cols: u16,
+        x_pixels: u16,
+        y_pixels: u16,
+    ) -> Result<(), AdbError> {
+        let size = format!("{}x{},{}x{}", rows, cols, x_pixels, y_pixels);
+        write_packet(&mut self.conn, ID_WINDOW_SIZE_CHANGE, size.as_bytes())
+    }
+
+    /// Block until the next output or the exit of the remote process.
+    pub fn read(&mut self) -> Result<ShellEvent, AdbError> {
+        if let Some(exit_code) = self.exit_code {
+            return Ok(ShellEvent::Exit(exit_code));
+        }
+        loop {
+            let (id, data) = read_packet(&mut self.conn)?;
+            match id {
+                ID_STDOUT => return Ok(ShellEvent::Stdout(data)),
+                ID_STDERR => return Ok(ShellEvent::Stderr(data)),
+                ID_EXIT => {
+                    let exit_code = data.first().copied().unwrap_or(0) as i32;
+                    self.exit_code = Some(exit_code);
+                    return Ok(ShellEvent::Exit(exit_code));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ShellEvent;
+    use crate::client::AdbClient;
+    use crate::device::{AdbDevice, ShellMixin};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time;
+
+    fn read_command(stream: &mut TcpStream) -> String {
+        let mut length = [0; 4];
+        stream.read_exact(&mut length).unwrap();
+        let length = usize::from_str_radix(std::str::from_utf8(&length).unwrap(), 16).unwrap();
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).unwrap();
+        String::from_utf8(body).unwrap()
+    }
+
+    fn packet(id: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![id];
+        packet.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    #[test]
+    fn test_shell_v2() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_command(&mut stream), "host-serial:serial1:features");
+            stream.write_all(b"OKAY0010shell_v2,cmd,abb").unwrap();
+
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_command(&mut stream), "host:transport:serial1");
+            stream.write_all(b"OKAY").unwrap();
+            let command = read_command(&mut stream);
+            stream.write_all(b"OKAY").unwrap();
+            let mut close_stdin = [0; 5];
+            stream.read_exact(&mut close_stdin).unwrap();
+            assert_eq!(close_stdin, packet(super::ID_CLOSE_STDIN, b"")[..]);
+            stream
+                .write_all(&packet(super::ID_STDOUT, b"out\n"))
+                .unwrap();
+            stream
+                .write_all(&packet(super::ID_STDERR, b"Failure [not installed]\n"))
+                .unwrap();
+            stream.write_all(&packet(super::ID_EXIT, &[1])).unwrap();
+            command
+        });
+
+        let client = AdbClient::new(String::from("127.0.0.1"), port, time::Duration::new(10, 0));
+        let device = AdbDevice::new(ShellMixin::new(client, String::from("serial1"), 0, None));
+        let output = device.shell_v2("pm uninstall com.example").unwrap();
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr_str(), "Failure [not installed]\n");
+        assert_eq!(output.exit_code, 1);
+        assert!(!output.success());
+        assert_eq!(
+            server.join().unwrap(),
+            "shell,v2,raw:pm uninstall com.example"
+        );
+    }
+
+    #[test]
+    fn test_shell_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_command(&mut stream);
+            stream.write_all(b"OKAY0008shell_v2").unwrap();
+
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_command(&mut stream), "host:transport:serial1");
+            stream.write_all(b"OKAY").unwrap();
+            let command = read_command(&mut stream);
+            stream.write_all(b"OKAY").unwrap();
+
+            let mut received = Vec::new();
+            for _ in 0..3 {
+                let mut header = [0; 5];
+                stream.read_exact(&mut header).unwrap();
+                let length = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+                let mut data = vec![0; length as usize];
+                stream.read_exact(&mut data).unwrap();
+                received.push((header[0], data));
+            }
+            stream.write_all(&packet(super::ID_STDOUT, b"3\n")).unwrap();
+            stream.write_all(&packet(super::ID_EXIT, &[0])).unwrap();
+            (command, received)
+        });
+
+        let client = AdbClient::new(String::from("127.0.0.1"), port, time::Duration::new(10, 0));
+        let device = AdbDevice::new(ShellMixin::new(client, String::from("serial1"), 0, None));
+        let mut session = device.shell_session("sqlite3", true).unwrap();
+        session.resize(24, 80, 0, 0).unwrap();
+        session.write_stdin(b"select 1 + 2;\n").unwrap();
+        session.close_stdin().unwrap();
+        assert_eq!(session.read().unwrap(), ShellEvent::Stdout(b"3\n".to_vec()));
+        assert_eq!(session.read().unwrap(), ShellEvent::Exit(0));
+        assert_eq!(session.exit_code(), Some(0));
+
+        let (command, received) = server.join().unwrap();
+        assert_eq!(command, "shell,v2,TERM=xterm-256color,pty:sqlite3");
+        assert_eq!(
+            received[0],
+            (super::ID_WINDOW_SIZE_CHANGE, b"24x80,0x0".to_vec())
+        );
+        assert_eq!(received[1], (super::ID_STDIN, b"select 1 + 2;\n".to_vec()));
+        assert_eq!(received[2], (super::ID_CLOSE_STDIN, Vec::new()));
+    }
+}
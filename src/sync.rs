@@ -0,0 +1,1438 @@
+use crate::client::AdbConnection;
+use crate::device::AdbDevice;
+use crate::error::AdbError;
+use crate::proto::{FeatureSet, FileInfo, ShellOutput};
+use std::collections::BTreeMap;
+use std::fs::{self, File, Metadata, Permissions};
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+// https://android.googlesource.com/platform/packages/modules/adb/+/refs/heads/main/SYNC.TXT
+const ID_SEND: &[u8; 4] = b"SEND";
+const ID_RECV: &[u8; 4] = b"RECV";
+const ID_SEND_V2: &[u8; 4] = b"SND2";
+const ID_RECV_V2: &[u8; 4] = b"RCV2";
+const ID_STAT: &[u8; 4] = b"STAT";
+const ID_STAT_V2: &[u8; 4] = b"STA2";
+const ID_LSTAT_V2: &[u8; 4] = b"LST2";
+const ID_LIST: &[u8; 4] = b"LIST";
+const ID_LIST_V2: &[u8; 4] = b"LIS2";
+const ID_DENT: &[u8; 4] = b"DENT";
+const ID_DENT_V2: &[u8; 4] = b"DNT2";
+const ID_DATA: &[u8; 4] = b"DATA";
+const ID_DONE: &[u8; 4] = b"DONE";
+const ID_OKAY: &[u8; 4] = b"OKAY";
+const ID_FAIL: &[u8; 4] = b"FAIL";
+
+const SYNC_DATA_MAX: usize = 64 * 1024;
+// requests carry a 4 hex digit length, batched shell commands stay well below it
+const SHELL_COMMAND_MAX: usize = 32 * 1024;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const ENOENT: u32 = 2;
+// sizes of the fixed part of the responses, without the 4 byte id
+const STAT_V1_SIZE: usize = 12;
+const STAT_V2_SIZE: usize = 68;
+const DENT_V1_SIZE: usize = 16;
+const DENT_V2_SIZE: usize = 72;
+
+/// Compression of the SND2/RCV2 data stream, `Any` picks the best one the device advertises.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Any,
+    None,
+    Brotli,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    fn flag(&self) -> u32 {
+        match self {
+            Compression::Brotli => 1,
+            Compression::Lz4 => 2,
+            Compression::Zstd => 4,
+            _ => 0,
+        }
+    }
+
+    fn feature(&self) -> &'static str {
+        match self {
+            Compression::Brotli => FeatureSet::SENDRECV_V2_BROTLI,
+            Compression::Lz4 => FeatureSet::SENDRECV_V2_LZ4,
+            Compression::Zstd => FeatureSet::SENDRECV_V2_ZSTD,
+            _ => FeatureSet::SENDRECV_V2,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Sync {
+    device: AdbDevice,
+    compression: Compression,
+    features: FeatureSet,
+}
+
+impl Sync {
+    /// The device's cached `features` pick the v2 requests, they are only queried when the
+    /// device has none yet.
+    pub fn new(device: AdbDevice) -> Result<Sync, AdbError> {
+        let features = device.features()?.clone();
+        Ok(Sync {
+            device,
+            compression: Compression::Any,
+            features,
+        })
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Sync {
+        self.compression = compression;
+        self
+    }
+
+    pub fn features(&self) -> &FeatureSet {
+        &self.features
+    }
+
+    /// `None` when the device only speaks SEND/RECV, otherwise the SND2/RCV2 compression to use.
+    fn negotiate(&self) -> Option<Compression> {
+        let supports = |compression: Compression| self.features.contains(compression.feature());
+        if !supports(Compression::None) {
+            return None;
+        }
+        let compression = match self.compression {
+            Compression::Any => [Compression::Zstd, Compression::Lz4, Compression::Brotli]
+                .into_iter()
+                .find(|compression| supports(*compression))
+                .unwrap_or(Compression::None),
+            compression if supports(compression) => compression,
+            _ => Compression::None,
+        };
+        Some(compression)
+    }
+
+    fn prepare_sync(&self) -> Result<AdbConnection, AdbError> {
+        let shell_mixin = &self.device.shell_mixin;
+        let mut conn = shell_mixin.client.transport(&shell_mixin.selector())?;
+        conn.send_command("sync:")?;
+        conn.check_oky()?;
+        Ok(conn)
+    }
+
+    /// Push everything readable from `src` to `dst` on the device, returns the number of bytes sent.
+    /// `mode` holds the permission bits only, the regular file type is added here.
+    pub fn push<R: Read>(
+        &self,
+        src: &mut R,
+        dst: &str,
+        mode: u32,
+        mtime: u32,
+    ) -> Result<usize, AdbError> {
+        self.send(self.negotiate(), src, dst, S_IFREG | (mode & 0o7777), mtime)
+    }
+
+    /// Create the symlink `dst` pointing to `target`. Like adb itself this always uses a plain
+    /// SEND, the target is never compressed.
+    pub fn push_symlink(&self, target: &str, dst: &str, mtime: u32) -> Result<usize, AdbError> {
+        self.send(None, &mut target.as_bytes(), dst, S_IFLNK | 0o777, mtime)
+    }
+
+    // `mode` includes the file type, `None` sends a v1 SEND
+    fn send<R: Read>(
+        &self,
+        compression: Option<Compression>,
+        src: &mut R,
+        dst: &str,
+        mode: u32,
+        mtime: u32,
+    ) -> Result<usize, AdbError> {
+        let mut conn = self.prepare_sync()?;
+        match compression {
+            Some(compression) => {
+                send_request(&mut conn, ID_SEND_V2, dst.as_bytes())?;
+                let mut request = ID_SEND_V2.to_vec();
+                request.extend_from_slice(&mode.to_le_bytes());
+                request.extend_from_slice(&compression.flag().to_le_bytes());
+                conn.send_bytes(&request)?;
+            }
+            None => {
+                let path = format!("{},{}", dst, mode);
+                send_request(&mut conn, ID_SEND, path.as_bytes())?;
+            }
+        }
+
+        let mut writer = DataWriter::new(&mut conn);
+        let total = match compression.unwrap_or(Compression::None) {
+            Compression::Brotli => {
+                let mut encoder = brotli::CompressorWriter::new(&mut writer, 4096, 5, 22);
+                let total = copy_data(src, &mut encoder)?;
+                // finishes the brotli stream, a failed send shows up in the final flush below
+                encoder.into_inner();
+                total
+            }
+            Compression::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(&mut writer);
+                let total = copy_data(src, &mut encoder)?;
+                encoder
+                    .finish()
+                    .map_err(|error| write_error(error.into()))?;
+                total
+            }
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(&mut writer, 0).map_err(write_error)?;
+                let total = copy_data(src, &mut encoder)?;
+                encoder.finish().map_err(write_error)?;
+                total
+            }
+            _ => copy_data(src, &mut writer)?,
+        };
+        writer.flush().map_err(write_error)?;
+        send_header(&mut conn, ID_DONE, mtime)?;
+
+        let (id, length) = read_header(&mut conn)?;
+        match &id {
+            ID_OKAY => Ok(total),
+            ID_FAIL => Err(read_fail(&mut conn, length)?),
+            _ => Err(unexpected_id(&id)),
+        }
+    }
+
+    /// Push a local file, keeping its permission bits and modification time.
+    pub fn push_file(&self, src: &Path, dst: &str) -> Result<usize, AdbError> {
+        let mut file = match File::open(src) {
+            Ok(file) => file,
+            Err(error) => {
+                return Err(AdbError::FileIoError {
+                    source: Box::new(error),
+                })
+            }
+        };
+        let metadata = match file.metadata() {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                return Err(AdbError::FileIoError {
+                    source: Box::new(error),
+                })
+            }
+        };
+        self.push(
+            &mut file,
+            dst,
+            metadata.permissions().mode(),
+            local_mtime(&metadata),
+        )
+    }
+
+    /// Mirror the local directory `src` to `dst` on the device, returns the number of bytes sent.
+    /// Files keep their mode and mtime, symlinks are recreated on the device and every directory,
+    /// including empty ones, gets its permission bits once its content is pushed.
+    pub fn push_dir(&self, src: &Path, dst: &str) -> Result<usize, AdbError> {
+        let mut dirs = Vec::new();
+        let mut entries = Vec::new();
+        collect_local(src, dst.trim_end_matches('/'), &mut dirs, &mut entries)?;
+
+        let paths = dirs
+            .iter()
+            .map(|(path, _)| shell_quote(path))
+            .collect::<Vec<String>>();
+        self.shell_batched("mkdir -p", &paths)?;
+
+        let mut total = 0;
+        for (local, remote, metadata) in entries {
+            if metadata.file_type().is_symlink() {
+                let target = match fs::read_link(&local) {
+                    Ok(target) => target,
+                    Err(error) => {
+                        return Err(AdbError::FileIoError {
+                            source: Box::new(error),
+                        })
+                    }
+                };
+                total +=
+                    self.push_symlink(&target.to_string_lossy(), &remote, local_mtime(&metadata))?;
+            } else {
+                total += self.push_file(&local, &remote)?;
+            }
+        }
+
+        // applied last, a read-only directory could not be filled anymore
+        let mut modes: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+        for (path, mode) in dirs {
+            modes
+                .entry(mode & 0o7777)
+                .or_default()
+                .push(shell_quote(&path));
+        }
+        for (mode, paths) in modes {
+            self.shell_batched(&format!("chmod {:o}", mode), &paths)?;
+        }
+        Ok(total)
+    }
+
+    // run `cmd` with as many of the quoted `args` per call as fit in SHELL_COMMAND_MAX
+    fn shell_batched(&self, cmd: &str, args: &[String]) -> Result<(), AdbError> {
+        let mut batches: Vec<String> = Vec::new();
+        for arg in args {
+            match batches.last_mut() {
+                Some(batch) if batch.len() + 1 + arg.len() <= SHELL_COMMAND_MAX => {
+                    batch.push(' ');
+                    batch.push_str(arg);
+                }
+                _ => batches.push(format!("{} {}", cmd, arg)),
+            }
+        }
+        for batch in batches {
+            let output = self.device.shell_v2(&batch)?;
+            if !output.success() {
+                return Err(AdbError::SyncError {
+                    message: format!("{}: {}", cmd, shell_message(&output)),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// lstat on the device, a missing path is reported with a zero mode.
+    /// Uses LST2 when the device supports `stat_v2` and STAT otherwise.
+    pub fn stat(&self, path: &str) -> Result<FileInfo, AdbError> {
+        if !self.features.stat_v2() {
+            return self.stat_v1(path);
+        }
+        match self.stat_raw(ID_LSTAT_V2, path)? {
+            (0, info) => Ok(info),
+            (ENOENT, _) => Ok(FileInfo {
+                path: path.to_string(),
+                mode: 0,
+                size: 0,
+                mtime: 0,
+                uid: None,
+                gid: None,
+                inode: None,
+                nlink: None,
+            }),
+            (error, _) => Err(stat_error(path, error)),
+        }
+    }
+
+    fn stat_v1(&self, path: &str) -> Result<FileInfo, AdbError> {
+        let mut conn = self.prepare_sync()?;
+        send_request(&mut conn, ID_STAT, path.as_bytes())?;
+        let id = read_id(&mut conn)?;
+        if &id != ID_STAT {
+            return Err(unexpected_id(&id));
+        }
+        let body = conn.read_bytes(STAT_V1_SIZE)?;
+        Ok(FileInfo {
+            path: path.to_string(),
+            mode: le_u32(&body, 0),
+            size: le_u32(&body, 4) as u64,
+            mtime: le_u32(&body, 8) as i64,
+            uid: None,
+            gid: None,
+            inode: None,
+            nlink: None,
+        })
+    }
+
+    /// STA2, follows symlinks and needs the `stat_v2` feature.
+    pub fn stat_v2(&self, path: &str) -> Result<FileInfo, AdbError> {
+        self.stat_with(ID_STAT_V2, path)
+    }
+
+    /// LST2, does not follow symlinks and needs the `stat_v2` feature.
+    pub fn lstat_v2(&self, path: &str) -> Result<FileInfo, AdbError> {
+        self.stat_with(ID_LSTAT_V2, path)
+    }
+
+    fn stat_with(&self, request: &[u8; 4], path: &str) -> Result<FileInfo, AdbError> {
+        match self.stat_raw(request, path)? {
+            (0, info) => Ok(info),
+            (error, _) => Err(stat_error(path, error)),
+        }
+    }
+
+    fn stat_raw(&self, request: &[u8; 4], path: &str) -> Result<(u32, FileInfo), AdbError> {
+        let mut conn = self.prepare_sync()?;
+        send_request(&mut conn, request, path.as_bytes())?;
+        let id = read_id(&mut conn)?;
+        if &id != request {
+            return Err(unexpected_id(&id));
+        }
+        let body = conn.read_bytes(STAT_V2_SIZE)?;
+        Ok(parse_stat_v2(path.to_string(), &body))
+    }
+
+    /// Uses LIS2 when the device supports `ls_v2` and LIST otherwise.
+    pub fn list(&self, path: &str) -> Result<SyncDirIter, AdbError> {
+        if self.features.ls_v2() {
+            return self.list_v2(path);
+        }
+        self.list_with(ID_LIST, path)
+    }
+
+    /// LIS2, needs the `ls_v2` feature.
+    pub fn list_v2(&self, path: &str) -> Result<SyncDirIter, AdbError> {
+        self.list_with(ID_LIST_V2, path)
+    }
+
+    fn list_with(&self, request: &[u8; 4], path: &str) -> Result<SyncDirIter, AdbError> {
+        let mut conn = self.prepare_sync()?;
+        send_request(&mut conn, request, path.as_bytes())?;
+        Ok(SyncDirIter {
+            conn,
+            v2: request == ID_LIST_V2,
+            done: false,
+        })
+    }
+
+    /// Pull `src` from the device into `dst`, returns the number of bytes received.
+    pub fn pull<W: Write>(&self, src: &str, dst: &mut W) -> Result<usize, AdbError> {
+        let compression = self.negotiate();
+        let mut conn = self.prepare_sync()?;
+        match compression {
+            Some(compression) => {
+                send_request(&mut conn, ID_RECV_V2, src.as_bytes())?;
+                send_header(&mut conn, ID_RECV_V2, compression.flag())?;
+            }
+            None => send_request(&mut conn, ID_RECV, src.as_bytes())?,
+        }
+
+        let mut reader = DataReader::new(&mut conn);
+        let res = match compression.unwrap_or(Compression::None) {
+            Compression::Brotli => {
+                copy_data(&mut brotli::Decompressor::new(&mut reader, 4096), dst)
+            }
+            Compression::Lz4 => {
+                copy_data(&mut lz4_flex::frame::FrameDecoder::new(&mut reader), dst)
+            }
+            Compression::Zstd => match zstd::Decoder::new(&mut reader) {
+                Ok(mut decoder) => copy_data(&mut decoder, dst),
+                Err(error) => Err(CopyError::Read(error)),
+            },
+            _ => copy_data(&mut reader, dst),
+        };
+        // the decoders stop at the end of the compressed stream, DONE may still be pending
+        let res = res.and_then(
+            |total| match std::io::copy(&mut reader, &mut std::io::sink()) {
+                Ok(_) => Ok(total),
+                Err(error) => Err(CopyError::Read(error)),
+            },
+        );
+        let total = match res {
+            Ok(total) => total,
+            Err(CopyError::Read(error)) => {
+                return Err(reader.error.take().unwrap_or(AdbError::ParseResponseError {
+                    source: Box::new(error),
+                }))
+            }
+            Err(CopyError::Write(error)) => {
+                return Err(AdbError::FileIoError {
+                    source: Box::new(error),
+                })
+            }
+        };
+        if let Err(error) = dst.flush() {
+            return Err(AdbError::FileIoError {
+                source: Box::new(error),
+            });
+        }
+        Ok(total)
+    }
+
+    /// Pull `src` into a local file, the partial file is removed when the transfer fails.
+    pub fn pull_file(&self, src: &str, dst: &Path) -> Result<usize, AdbError> {
+        let mut file = match File::create(dst) {
+            Ok(file) => file,
+            Err(error) => {
+                return Err(AdbError::FileIoError {
+                    source: Box::new(error),
+                })
+            }
+        };
+        let res = self.pull(src, &mut file);
+        if res.is_err() {
+            drop(file);
+            let _ = fs::remove_file(dst);
+        }
+        res
+    }
+
+    /// Mirror the device directory `src` into the local `dst`, returns the number of bytes received.
+    /// Modes and mtimes are applied to files and directories, symlinks are recreated locally.
+    pub fn pull_dir(&self, src: &str, dst: &Path) -> Result<usize, AdbError> {
+        let src = src.trim_end_matches('/');
+        // the trailing slash makes the device resolve a symlinked directory such as /sdcard
+        let info = self.stat(&format!("{}/", src))?;
+        if !info.is_dir() {
+            return Err(AdbError::SyncError {
+                message: format!("{}: Not a directory", src),
+            });
+        }
+        self.pull_tree(src, dst, &info)
+    }
+
+    fn pull_tree(&self, src: &str, dst: &Path, info: &FileInfo) -> Result<usize, AdbError> {
+        if let Err(error) = fs::create_dir_all(dst) {
+            return Err(AdbError::FileIoError {
+                source: Box::new(error),
+            });
+        }
+        let entries = self
+            .list(src)?
+            .collect::<Result<Vec<FileInfo>, AdbError>>()?;
+
+        let mut total = 0;
+        for entry in entries {
+            // the name comes from the device, it must not escape `dst`
+            if entry.path.is_empty()
+                || entry.path == "."
+                || entry.path == ".."
+                || entry.path.contains('/')
+            {
+                return Err(AdbError::SyncError {
+                    message: format!("{}: invalid entry name '{}'", src, entry.path),
+                });
+            }
+            let remote = format!("{}/{}", src, entry.path);
+            let local = dst.join(&entry.path);
+            if entry.is_dir() {
+                total += self.pull_tree(&remote, &local, &entry)?;
+            } else if entry.is_symlink() {
+                let target = self.read_link(&remote)?;
+                let _ = fs::remove_file(&local);
+                if let Err(error) = std::os::unix::fs::symlink(target, &local) {
+                    return Err(AdbError::FileIoError {
+                        source: Box::new(error),
+                    });
+                }
+            } else if entry.is_file() {
+                total += self.pull_file(&remote, &local)?;
+                set_local_attributes(&local, &entry)?;
+            }
+        }
+        // applied last, writing the children would bump the directory mtime again
+        set_local_attributes(dst, info)?;
+        Ok(total)
+    }
+
+    fn read_link(&self, path: &str) -> Result<String, AdbError> {
+        let output = self
+            .device
+            .shell_v2(&format!("readlink {}", shell_quote(path)))?;
+        let target = output
+            .stdout_str()
+            .trim_end_matches(['\r', '\n'])
+            .to_string();
+        if !output.success() || target.is_empty() {
+            return Err(AdbError::SyncError {
+                message: format!("readlink {}: {}", path, shell_message(&output)),
+            });
+        }
+        Ok(target)
+    }
+}
+
+/// Lazily reads the directory entries of a LIST/LIS2 request, `.` and `..` are skipped.
+#[derive(Debug)]
+pub struct SyncDirIter {
+    conn: AdbConnection,
+    v2: bool,
+    done: bool,
+}
+
+impl SyncDirIter {
+    fn read_entry(&mut self) -> Result<Option<FileInfo>, AdbError> {
+        let id = read_id(&mut self.conn)?;
+        let expected = if self.v2 { ID_DENT_V2 } else { ID_DENT };
+        let size = if self.v2 { DENT_V2_SIZE } else { DENT_V1_SIZE };
+        if &id == ID_DONE {
+            self.conn.read_bytes(size)?;
+            return Ok(None);
+        }
+        if &id == ID_FAIL {
+            let length = le_u32(&self.conn.read_bytes(4)?, 0);
+            return Err(read_fail(&mut self.conn, length)?);
+        }
+        if &id != expected {
+            return Err(unexpected_id(&id));
+        }
+        let body = self.conn.read_bytes(size)?;
+        let name_length = le_u32(&body, size - 4) as usize;
+        let name = self.conn.read_bytes(name_length)?;
+        let name = String::from_utf8_lossy(&name).to_string();
+        let info = if self.v2 {
+            match parse_stat_v2(name, &body) {
+                (0, info) => info,
+                (error, info) => return Err(stat_error(&info.path, error)),
+            }
+        } else {
+            FileInfo {
+                path: name,
+                mode: le_u32(&body, 0),
+                size: le_u32(&body, 4) as u64,
+                mtime: le_u32(&body, 8) as i64,
+                uid: None,
+                gid: None,
+                inode: None,
+                nlink: None,
+            }
+        };
+        Ok(Some(info))
+    }
+}
+
+impl Iterator for SyncDirIter {
+    type Item = Result<FileInfo, AdbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            match self.read_entry() {
+                Ok(Some(info)) if info.path == "." || info.path == ".." => continue,
+                Ok(Some(info)) => return Some(Ok(info)),
+                Ok(None) => self.done = true,
+                Err(error) => {
+                    self.done = true;
+                    return Some(Err(error));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Splits everything written into DATA frames of at most 64 KiB.
+struct DataWriter<'a> {
+    conn: &'a mut AdbConnection,
+    buff: Vec<u8>,
+}
+
+impl<'a> DataWriter<'a> {
+    fn new(conn: &'a mut AdbConnection) -> DataWriter<'a> {
+        DataWriter {
+            conn,
+            buff: Vec::with_capacity(SYNC_DATA_MAX),
+        }
+    }
+
+    fn send_frames(&mut self, all: bool) -> std::io::Result<()> {
+        while self.buff.len() >= SYNC_DATA_MAX || (all && !self.buff.is_empty()) {
+            let n = self.buff.len().min(SYNC_DATA_MAX);
+            if let Err(error) = send_request(self.conn, ID_DATA, &self.buff[..n]) {
+                return Err(std::io::Error::other(error));
+            }
+            self.buff.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl Write for DataWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buff.extend_from_slice(data);
+        self.send_frames(false)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_frames(true)
+    }
+}
+
+/// Yields the payload of DATA frames until DONE, a FAIL frame is kept in `error`.
+struct DataReader<'a> {
+    conn: &'a mut AdbConnection,
+    chunk: Vec<u8>,
+    pos: usize,
+    done: bool,
+    error: Option<AdbError>,
+}
+
+impl<'a> DataReader<'a> {
+    fn new(conn: &'a mut AdbConnection) -> DataReader<'a> {
+        DataReader {
+            conn,
+            chunk: Vec::new(),
+            pos: 0,
+            done: false,
+            error: None,
+        }
+    }
+
+    fn next_chunk(&mut self) -> Result<(), AdbError> {
+        let (id, length) = read_header(self.conn)?;
+        match &id {
+            ID_DATA => {
+                self.chunk = self.conn.read_bytes(length as usize)?;
+                self.pos = 0;
+                Ok(())
+            }
+            ID_DONE => {
+                self.done = true;
+                Ok(())
+            }
+            ID_FAIL => Err(read_fail(self.conn, length)?),
+            _ => Err(unexpected_id(&id)),
+        }
+    }
+}
+
+impl Read for DataReader<'_> {
+    fn read(&mut self, buff: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.chunk.len() && !self.done {
+            if let Err(error) = self.next_chunk() {
+                let message = format!("{:?}", error);
+                self.error = Some(error);
+                return Err(std::io::Error::other(message));
+            }
+        }
+        let n = buff.len().min(self.chunk.len() - self.pos);
+        buff[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+enum CopyError {
+    Read(std::io::Error),
+    Write(std::io::Error),
+}
+
+impl From<CopyError> for AdbError {
+    // reading the local source failed or sending to the device did
+    fn from(error: CopyError) -> Self {
+        match error {
+            CopyError::Read(error) => AdbError::FileIoError {
+                source: Box::new(error),
+            },
+            CopyError::Write(error) => write_error(error),
+        }
+    }
+}
+
+fn copy_data<R: Read + ?Sized, W: Write + ?Sized>(
+    src: &mut R,
+    dst: &mut W,
+) -> Result<usize, CopyError> {
+    let mut total = 0;
+    let mut buff = vec![0; SYNC_DATA_MAX];
+    loop {
+        let n = match src.read(&mut buff) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(CopyError::Read(error)),
+        };
+        if let Err(error) = dst.write_all(&buff[..n]) {
+            return Err(CopyError::Write(error));
+        }
+        total += n;
+    }
+}
+
+// errors of the connection itself come back out of the `DataWriter` unchanged
+fn write_error(error: std::io::Error) -> AdbError {
+    match error.downcast::<AdbError>() {
+        Ok(error) => error,
+        Err(error) => AdbError::TcpWriteError {
+            source: Box::new(error),
+        },
+    }
+}
+
+type LocalEntry = (PathBuf, String, Metadata);
+
+fn collect_local(
+    src: &Path,
+    dst: &str,
+    dirs: &mut Vec<(String, u32)>,
+    entries: &mut Vec<LocalEntry>,
+) -> Result<(), AdbError> {
+    let metadata = match fs::metadata(src) {
+        Ok(metadata) => metadata,
+        Err(error) => {
+            return Err(AdbError::FileIoError {
+                source: Box::new(error),
+            })
+        }
+    };
+    dirs.push((dst.to_string(), metadata.permissions().mode()));
+    let read_dir = match fs::read_dir(src) {
+        Ok(read_dir) => read_dir,
+        Err(error) => {
+            return Err(AdbError::FileIoError {
+                source: Box::new(error),
+            })
+        }
+    };
+    for entry in read_dir {
+        let (path, metadata) = match entry.and_then(|e| Ok((e.path(), e.metadata()?))) {
+            Ok(entry) => entry,
+            Err(error) => {
+                return Err(AdbError::FileIoError {
+                    source: Box::new(error),
+                })
+            }
+        };
+        let remote = format!(
+            "{}/{}",
+            dst,
+            path.file_name().unwrap_or_default().to_string_lossy()
+        );
+        if metadata.is_dir() {
+            collect_local(&path, &remote, dirs, entries)?;
+        } else if metadata.is_file() || metadata.file_type().is_symlink() {
+            entries.push((path, remote, metadata));
+        }
+    }
+    Ok(())
+}
+
+fn local_mtime(metadata: &Metadata) -> u32 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+fn set_local_attributes(path: &Path, info: &FileInfo) -> Result<(), AdbError> {
+    let mtime = UNIX_EPOCH + Duration::from_secs(info.mtime.max(0) as u64);
+    let res = File::open(path)
+        .and_then(|file| file.set_modified(mtime))
+        .and_then(|_| fs::set_permissions(path, Permissions::from_mode(info.mode & 0o7777)));
+    match res {
+        Ok(_) => Ok(()),
+        Err(error) => Err(AdbError::FileIoError {
+            source: Box::new(error),
+        }),
+    }
+}
+
+// the shell v1 fallback merges stderr into stdout
+fn shell_message(output: &ShellOutput) -> String {
+    let message = if output.stderr.is_empty() {
+        output.stdout_str()
+    } else {
+        output.stderr_str()
+    };
+    message.trim_end().to_string()
+}
+
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+fn send_header(conn: &mut AdbConnection, id: &[u8; 4], value: u32) -> Result<(), AdbError> {
+    let mut header = [0; 8];
+    header[..4].copy_from_slice(id);
+    header[4..].copy_from_slice(&value.to_le_bytes());
+    conn.send_bytes(&header)
+}
+
+fn send_request(conn: &mut AdbConnection, id: &[u8; 4], data: &[u8]) -> Result<(), AdbError> {
+    let mut request = Vec::with_capacity(8 + data.len());
+    request.extend_from_slice(id);
+    request.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    request.extend_from_slice(data);
+    conn.send_bytes(&request)
+}
+
+fn read_header(conn: &mut AdbConnection) -> Result<([u8; 4], u32), AdbError> {
+    let header = conn.read_bytes(8)?;
+    let mut id = [0; 4];
+    id.copy_from_slice(&header[..4]);
+    Ok((id, le_u32(&header, 4)))
+}
+
+fn read_id(conn: &mut AdbConnection) -> Result<[u8; 4], AdbError> {
+    let data = conn.read_bytes(4)?;
+    let mut id = [0; 4];
+    id.copy_from_slice(&data);
+    Ok(id)
+}
+
+fn le_u32(buff: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buff[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn le_u64(buff: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buff[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+// error, dev, ino, mode, nlink, uid, gid, size, atime, mtime, ctime
+fn parse_stat_v2(path: String, body: &[u8]) -> (u32, FileInfo) {
+    let info = FileInfo {
+        path,
+        mode: le_u32(body, 20),
+        size: le_u64(body, 36),
+        mtime: le_u64(body, 52) as i64,
+        uid: Some(le_u32(body, 28)),
+        gid: Some(le_u32(body, 32)),
+        inode: Some(le_u64(body, 12)),
+        nlink: Some(le_u32(body, 24)),
+    };
+    (le_u32(body, 0), info)
+}
+
+fn stat_error(path: &str, error: u32) -> AdbError {
+    AdbError::SyncError {
+        message: format!(
+            "{}: {}",
+            path,
+            std::io::Error::from_raw_os_error(error as i32)
+        ),
+    }
+}
+
+fn read_fail(conn: &mut AdbConnection, length: u32) -> Result<AdbError, AdbError> {
+    let message = conn.read_bytes(length as usize)?;
+    Ok(AdbError::SyncError {
+        message: String::from_utf8_lossy(&message).to_string(),
+    })
+}
+
+fn unexpected_id(id: &[u8; 4]) -> AdbError {
+    AdbError::ResponseStatusError {
+        content: format!("unexpected sync response: {}", String::from_utf8_lossy(id)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Sync;
+    use crate::client::AdbClient;
+    use crate::device::{AdbDevice, ShellMixin};
+    use crate::error::AdbError;
+    use crate::proto::{FeatureSet, FileInfo, ShellOutput, TransportSelector};
+    use crate::testing::{MockDevice, MockServer};
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::path::Path;
+    use std::thread;
+    use std::time;
+    use std::time::UNIX_EPOCH;
+
+    fn read_command(stream: &mut TcpStream) -> String {
+        let mut length = [0; 4];
+        stream.read_exact(&mut length).unwrap();
+        let length = usize::from_str_radix(std::str::from_utf8(&length).unwrap(), 16).unwrap();
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).unwrap();
+        String::from_utf8(body).unwrap()
+    }
+
+    fn read_frame(stream: &mut TcpStream) -> (String, Vec<u8>) {
+        let mut header = [0; 8];
+        stream.read_exact(&mut header).unwrap();
+        let id = String::from_utf8(header[..4].to_vec()).unwrap();
+        let value = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if id == "DONE" {
+            return (id, value.to_le_bytes().to_vec());
+        }
+        let mut data = vec![0; value as usize];
+        stream.read_exact(&mut data).unwrap();
+        (id, data)
+    }
+
+    /// A `Sync` with `features` whose one sync connection is answered by `handler`.
+    fn serve<T, F>(features: &str, handler: F) -> (Sync, thread::JoinHandle<T>)
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut TcpStream) -> T + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_command(&mut stream), "host:transport:emulator-5554");
+            stream.write_all(b"OKAY").unwrap();
+            assert_eq!(read_command(&mut stream), "sync:");
+            stream.write_all(b"OKAY").unwrap();
+            handler(&mut stream)
+        });
+        let client = AdbClient::new(String::from("127.0.0.1"), port, time::Duration::new(10, 0));
+        let shell_mixin = ShellMixin::new(client, String::from("emulator-5554"), 0, None)
+            .with_features(FeatureSet::parse(features));
+        (Sync::new(AdbDevice::new(shell_mixin)).unwrap(), server)
+    }
+
+    #[test]
+    fn test_transport_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let transport = read_command(&mut stream);
+            stream.write_all(b"OKAY").unwrap();
+            assert_eq!(read_command(&mut stream), "sync:");
+            stream.write_all(b"OKAY").unwrap();
+            let (id, path) = read_frame(&mut stream);
+            stream.write_all(b"STAT").unwrap();
+            stream.write_all(&[0; 12]).unwrap();
+            (transport, id, path)
+        });
+        let client = AdbClient::new(String::from("127.0.0.1"), port, time::Duration::new(10, 0));
+        // the serial of a TCP/IP device contains a colon, the transport id avoids it
+        let shell_mixin = ShellMixin::new(client, String::from("192.168.1.20:5555"), 7, None)
+            .with_features(FeatureSet::parse(""));
+        let sync = Sync::new(AdbDevice::new(shell_mixin)).unwrap();
+        assert_eq!(sync.stat("/sdcard/missing").unwrap().mode, 0);
+        assert_eq!(
+            server.join().unwrap(),
+            (
+                String::from("host:transport-id:7"),
+                String::from("STAT"),
+                b"/sdcard/missing".to_vec()
+            )
+        );
+    }
+
+    #[test]
+    fn test_push() {
+        let (sync, server) = serve("shell_v2,cmd,stat_v2", |stream| {
+            let mut frames = Vec::new();
+            loop {
+                let frame = read_frame(stream);
+                let done = frame.0 == "DONE";
+                frames.push(frame);
+                if done {
+                    break;
+                }
+            }
+            stream.write_all(b"OKAY\0\0\0\0").unwrap();
+            frames
+        });
+
+        let content = vec![7u8; 64 * 1024 + 10];
+        let sent = sync
+            .push(
+                &mut content.as_slice(),
+                "/data/local/tmp/a.bin",
+                0o644,
+                1234,
+            )
+            .unwrap();
+        assert_eq!(sent, content.len());
+
+        let frames = server.join().unwrap();
+        assert_eq!(frames[0].0, "SEND");
+        assert_eq!(frames[0].1, b"/data/local/tmp/a.bin,33188");
+        assert_eq!(frames[1].0, "DATA");
+        assert_eq!(frames[1].1.len(), 64 * 1024);
+        assert_eq!(frames[2].1.len(), 10);
+        assert_eq!(frames[3].0, "DONE");
+        assert_eq!(frames[3].1, 1234u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_pull() {
+        let (sync, server) = serve("shell_v2,cmd", |stream| {
+            let (id, path) = read_frame(stream);
+            stream.write_all(b"DATA\x05\0\0\0hello").unwrap();
+            stream.write_all(b"DATA\x02\0\0\0\xff\n").unwrap();
+            stream.write_all(b"DONE\0\0\0\0").unwrap();
+            (id, path)
+        });
+
+        let mut out = Vec::new();
+        assert_eq!(sync.pull("/sdcard/log.txt", &mut out).unwrap(), 7);
+        assert_eq!(out, b"hello\xff\n");
+        let (id, path) = server.join().unwrap();
+        assert_eq!(id, "RECV");
+        assert_eq!(path, b"/sdcard/log.txt");
+    }
+
+    #[test]
+    fn test_pull_fail() {
+        let (sync, server) = serve("", |stream| {
+            read_frame(stream);
+            let message = b"No such file or directory";
+            stream.write_all(b"FAIL").unwrap();
+            stream
+                .write_all(&(message.len() as u32).to_le_bytes())
+                .unwrap();
+            stream.write_all(message).unwrap();
+        });
+
+        let mut out = Vec::new();
+        match sync.pull("/sdcard/missing", &mut out) {
+            Err(AdbError::SyncError { message }) => {
+                assert_eq!(message, "No such file or directory")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_stat() {
+        let (sync, server) = serve("shell_v2,ls_v2", |stream| {
+            let request = read_frame(stream);
+            stream.write_all(b"STAT").unwrap();
+            for value in [0o100644u32, 42, 1700000000] {
+                stream.write_all(&value.to_le_bytes()).unwrap();
+            }
+            request
+        });
+
+        let info = sync.stat("/sdcard/a.txt").unwrap();
+        assert!(info.is_file());
+        assert_eq!(info.mode, 0o100644);
+        assert_eq!(info.size, 42);
+        assert_eq!(info.mtime, 1700000000);
+        assert_eq!(info.uid, None);
+        assert_eq!(
+            server.join().unwrap(),
+            (String::from("STAT"), b"/sdcard/a.txt".to_vec())
+        );
+    }
+
+    fn dent_v2(name: &str, mode: u32, size: u64) -> Vec<u8> {
+        let mut dent = b"DNT2".to_vec();
+        dent.extend_from_slice(&0u32.to_le_bytes()); // error
+        dent.extend_from_slice(&1u64.to_le_bytes()); // dev
+        dent.extend_from_slice(&77u64.to_le_bytes()); // ino
+        dent.extend_from_slice(&mode.to_le_bytes());
+        dent.extend_from_slice(&1u32.to_le_bytes()); // nlink
+        dent.extend_from_slice(&1000u32.to_le_bytes()); // uid
+        dent.extend_from_slice(&1015u32.to_le_bytes()); // gid
+        dent.extend_from_slice(&size.to_le_bytes());
+        for time in [1i64, 2, 3] {
+            dent.extend_from_slice(&time.to_le_bytes());
+        }
+        dent.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        dent.extend_from_slice(name.as_bytes());
+        dent
+    }
+
+    #[test]
+    fn test_list_v2() {
+        let (sync, server) = serve("", |stream| {
+            let request = read_frame(stream);
+            stream.write_all(&dent_v2(".", 0o40755, 0)).unwrap();
+            stream
+                .write_all(&dent_v2("my file.txt", 0o100600, 5))
+                .unwrap();
+            stream.write_all(&dent_v2("dir", 0o40700, 4096)).unwrap();
+            let mut done = b"DONE".to_vec();
+            done.resize(4 + 72, 0);
+            stream.write_all(&done).unwrap();
+            request
+        });
+
+        let entries: Vec<FileInfo> = sync
+            .list_v2("/sdcard")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, "my file.txt");
+        assert!(entries[0].is_file());
+        assert_eq!(entries[0].size, 5);
+        assert_eq!(entries[0].mtime, 2);
+        assert_eq!(entries[0].uid, Some(1000));
+        assert_eq!(entries[0].gid, Some(1015));
+        assert_eq!(entries[0].inode, Some(77));
+        assert_eq!(entries[0].nlink, Some(1));
+        assert!(entries[1].is_dir());
+        assert_eq!(server.join().unwrap().0, "LIS2");
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(super::shell_quote("/sdcard/my dir"), "'/sdcard/my dir'");
+        assert_eq!(super::shell_quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn test_push_v2_zstd() {
+        let (sync, server) = serve(
+            "sendrecv_v2,sendrecv_v2_brotli,sendrecv_v2_zstd",
+            |stream| {
+                let request = read_frame(stream);
+                let mut send_v2 = [0; 12];
+                stream.read_exact(&mut send_v2).unwrap();
+                let mut compressed = Vec::new();
+                loop {
+                    let (id, data) = read_frame(stream);
+                    if id == "DONE" {
+                        break;
+                    }
+                    assert!(data.len() <= 64 * 1024);
+                    compressed.extend_from_slice(&data);
+                }
+                stream.write_all(b"OKAY\0\0\0\0").unwrap();
+                (request, send_v2, compressed)
+            },
+        );
+
+        let content = b"compress me ".repeat(20000);
+        let sent = sync
+            .push(&mut content.as_slice(), "/sdcard/big.txt", 0o600, 1)
+            .unwrap();
+        assert_eq!(sent, content.len());
+
+        let (request, send_v2, compressed) = server.join().unwrap();
+        assert_eq!(request, (String::from("SND2"), b"/sdcard/big.txt".to_vec()));
+        assert_eq!(&send_v2[..4], b"SND2");
+        assert_eq!(send_v2[4..8], 0o100600u32.to_le_bytes());
+        assert_eq!(send_v2[8..], 4u32.to_le_bytes());
+        assert!(compressed.len() < content.len());
+        assert_eq!(zstd::decode_all(compressed.as_slice()).unwrap(), content);
+    }
+
+    #[test]
+    fn test_pull_v2_lz4() {
+        let content = b"0123456789".repeat(10000);
+        let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+        encoder.write_all(&content).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let (sync, server) = serve("sendrecv_v2,sendrecv_v2_lz4", move |stream| {
+            let request = read_frame(stream);
+            let mut recv_v2 = [0; 8];
+            stream.read_exact(&mut recv_v2).unwrap();
+            for chunk in compressed.chunks(1000) {
+                stream.write_all(b"DATA").unwrap();
+                stream
+                    .write_all(&(chunk.len() as u32).to_le_bytes())
+                    .unwrap();
+                stream.write_all(chunk).unwrap();
+            }
+            stream.write_all(b"DONE\0\0\0\0").unwrap();
+            (request, recv_v2)
+        });
+
+        let mut out = Vec::new();
+        assert_eq!(
+            sync.pull("/sdcard/digits", &mut out).unwrap(),
+            content.len()
+        );
+        assert_eq!(out, content);
+        let (request, recv_v2) = server.join().unwrap();
+        assert_eq!(request.0, "RCV2");
+        assert_eq!(&recv_v2[..4], b"RCV2");
+        assert_eq!(recv_v2[4..], 2u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_push_closed() {
+        let (sync, server) = serve("", |stream| read_frame(stream).0);
+        let content = vec![7u8; 16 * 1024 * 1024];
+        let res = sync.push(&mut content.as_slice(), "/data/local/tmp/a.bin", 0o644, 0);
+        assert!(matches!(res, Err(AdbError::ClosedByPeer)), "{:?}", res);
+        assert_eq!(server.join().unwrap(), "SEND");
+    }
+
+    #[test]
+    fn test_push_symlink() {
+        // zstd is negotiated for files, links still go uncompressed over SEND
+        let (sync, server) = serve("sendrecv_v2,sendrecv_v2_zstd", |stream| {
+            let frames = (0..3).map(|_| read_frame(stream)).collect::<Vec<_>>();
+            stream.write_all(b"OKAY\0\0\0\0").unwrap();
+            frames
+        });
+        sync.push_symlink("/system/bin/sh", "/data/local/tmp/sh", 1234)
+            .unwrap();
+
+        let frames = server.join().unwrap();
+        assert_eq!(frames[0].0, "SEND");
+        assert_eq!(frames[0].1, b"/data/local/tmp/sh,41471");
+        assert_eq!(
+            frames[1],
+            (String::from("DATA"), b"/system/bin/sh".to_vec())
+        );
+        assert_eq!(
+            frames[2],
+            (String::from("DONE"), 1234u32.to_le_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn test_pull_tree_entry_names() {
+        let (sync, server) = serve("", |stream| {
+            let request = read_frame(stream);
+            let name = b"../escape";
+            stream.write_all(b"DENT").unwrap();
+            for value in [0o100644u32, 5, 0, name.len() as u32] {
+                stream.write_all(&value.to_le_bytes()).unwrap();
+            }
+            stream.write_all(name).unwrap();
+            stream
+                .write_all(&[b"DONE".as_slice(), &[0; 16]].concat())
+                .unwrap();
+            request
+        });
+        let dst = std::env::temp_dir().join(format!("adbutils-names-{}", std::process::id()));
+        let info = FileInfo {
+            path: String::from("/sdcard/x"),
+            mode: 0o040755,
+            size: 0,
+            mtime: 0,
+            uid: None,
+            gid: None,
+            inode: None,
+            nlink: None,
+        };
+        match sync.pull_tree("/sdcard/x", &dst, &info) {
+            Err(AdbError::SyncError { message }) => {
+                assert_eq!(message, "/sdcard/x: invalid entry name '../escape'")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(server.join().unwrap().0, "LIST");
+        fs::remove_dir_all(&dst).unwrap();
+    }
+
+    #[test]
+    fn test_read_link() {
+        let server = MockServer::start().unwrap();
+        server.add_device(
+            MockDevice::new("emulator-5554")
+                .features("shell_v2")
+                .symlink("/sdcard/link", "a.txt")
+                .shell_output(
+                    "readlink '/sdcard/gone'",
+                    ShellOutput {
+                        stdout: Vec::new(),
+                        stderr: b"readlink: /sdcard/gone: No such file or directory\n".to_vec(),
+                        exit_code: 1,
+                    },
+                )
+                .shell("readlink '/sdcard/empty'", ""),
+        );
+        let sync = server
+            .client()
+            .device(TransportSelector::Any)
+            .unwrap()
+            .sync()
+            .unwrap();
+        assert_eq!(sync.read_link("/sdcard/link").unwrap(), "a.txt");
+        match sync.read_link("/sdcard/gone") {
+            Err(AdbError::SyncError { message }) => assert_eq!(
+                message,
+                "readlink /sdcard/gone: readlink: /sdcard/gone: No such file or directory"
+            ),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(matches!(
+            sync.read_link("/sdcard/empty"),
+            Err(AdbError::SyncError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_push_pull_dir() {
+        let server = MockServer::start().unwrap();
+        server.add_device(MockDevice::new("emulator-5554"));
+        let sync = server
+            .client()
+            .device(TransportSelector::Any)
+            .unwrap()
+            .sync()
+            .unwrap();
+
+        let root = std::env::temp_dir().join(format!("adbutils-tree-{}", std::process::id()));
+        let src = root.join("src");
+        fs::create_dir_all(src.join("empty")).unwrap();
+        fs::create_dir_all(src.join("ro")).unwrap();
+        fs::write(src.join("a.txt"), b"hello").unwrap();
+        fs::write(src.join("ro/b.bin"), [0u8, 1, 2]).unwrap();
+        symlink("a.txt", src.join("link")).unwrap();
+        fs::set_permissions(src.join("empty"), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::set_permissions(src.join("ro"), fs::Permissions::from_mode(0o555)).unwrap();
+
+        assert_eq!(sync.push_dir(&src, "/sdcard/tree/").unwrap(), 5 + 3 + 5);
+        let link = server.file("emulator-5554", "/sdcard/tree/link").unwrap();
+        assert_eq!(
+            (link.data.as_slice(), link.mode),
+            (b"a.txt".as_slice(), 0o120777)
+        );
+        assert_eq!(
+            server
+                .file("emulator-5554", "/sdcard/tree/ro/b.bin")
+                .unwrap()
+                .data,
+            [0, 1, 2]
+        );
+        assert_eq!(sync.stat("/sdcard/tree/ro").unwrap().mode, 0o040555);
+        assert_eq!(sync.stat("/sdcard/tree/empty").unwrap().mode, 0o040700);
+        match sync.push_dir(&src, "/sdcard/tree/a.txt") {
+            Err(AdbError::SyncError { message }) => {
+                assert_eq!(
+                    message,
+                    "mkdir -p: mkdir: '/sdcard/tree/a.txt': File exists"
+                )
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let dst = root.join("dst");
+        assert_eq!(sync.pull_dir("/sdcard/tree", &dst).unwrap(), 5 + 3);
+        assert_eq!(fs::read(dst.join("a.txt")).unwrap(), b"hello");
+        assert_eq!(fs::read(dst.join("ro/b.bin")).unwrap(), [0, 1, 2]);
+        assert_eq!(fs::read_link(dst.join("link")).unwrap(), Path::new("a.txt"));
+        let mode = |path: &str| fs::metadata(dst.join(path)).unwrap().permissions().mode();
+        assert_eq!(mode("empty"), 0o040700);
+        assert_eq!(mode("ro"), 0o040555);
+        let mtime = |path: &Path| {
+            let modified = fs::metadata(path).unwrap().modified().unwrap();
+            modified.duration_since(UNIX_EPOCH).unwrap().as_secs()
+        };
+        assert_eq!(mtime(&dst.join("a.txt")), mtime(&src.join("a.txt")));
+        // the shell commands in between reuse the features the session started with
+        let features = server
+            .requests()
+            .into_iter()
+            .filter(|request| request.ends_with(":features"))
+            .count();
+        assert_eq!(features, 1);
+
+        for dir in [&src, &dst] {
+            fs::set_permissions(dir.join("ro"), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_push_dir_batches() {
+        let server = MockServer::start().unwrap();
+        server.add_device(MockDevice::new("emulator-5554"));
+        let sync = server
+            .client()
+            .device(TransportSelector::Any)
+            .unwrap()
+            .sync()
+            .unwrap();
+
+        let src = std::env::temp_dir().join(format!("adbutils-dirs-{}", std::process::id()));
+        for i in 0..400 {
+            fs::create_dir_all(src.join(format!("{:0>100}", i))).unwrap();
+        }
+        assert_eq!(sync.push_dir(&src, "/sdcard/dirs").unwrap(), 0);
+        fs::remove_dir_all(&src).unwrap();
+
+        let mkdirs = server
+            .requests()
+            .into_iter()
+            .filter(|request| request.starts_with("shell:mkdir -p "))
+            .collect::<Vec<String>>();
+        assert_eq!(mkdirs.len(), 2);
+        assert!(mkdirs.iter().all(|request| request.len() < 32 * 1024 + 16));
+        let entries = sync
+            .list("/sdcard/dirs")
+            .unwrap()
+            .collect::<Result<Vec<FileInfo>, AdbError>>()
+            .unwrap();
+        assert_eq!(entries.len(), 400);
+    }
+}
@@ -0,0 +1,1250 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time;
+
+use crate::client::AdbClient;
+use crate::proto::{DeviceState, ForWardItem, ShellOutput, TransportSelector};
+use crate::shell;
+
+/// What `host:version` answers, the version of platform-tools 35.
+pub const SERVER_VERSION: u32 = 41;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+const SYNC_DATA_MAX: usize = 64 * 1024;
+const FIRST_FORWARD_PORT: u16 = 40000;
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(20);
+
+/// A file in the tree served over `sync:`, `mode` includes the file type bits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockFile {
+    pub data: Vec<u8>,
+    pub mode: u32,
+    pub mtime: u32,
+}
+
+/// A scripted device: its `devices-l` line, features, shell responses and file tree.
+/// Besides the scripted commands the shell understands `mkdir -p`, `chmod` and `readlink`
+/// on that tree; a directory without the owner write bit rejects pushed files. Over shell v2
+/// `cat` echoes stdin and only exits once it is closed.
+#[derive(Debug, Clone)]
+pub struct MockDevice {
+    serial: String,
+    state: DeviceState,
+    product: String,
+    model: String,
+    device: String,
+    usb: Option<String>,
+    features: String,
+    shell: HashMap<String, ShellOutput>,
+    files: BTreeMap<String, MockFile>,
+    // explicitly created directories and their mode, parents of files exist implicitly
+    dirs: BTreeMap<String, u32>,
+}
+
+impl MockDevice {
+    pub fn new(serial: &str) -> MockDevice {
+        MockDevice {
+            serial: serial.to_string(),
+            state: DeviceState::Device,
+            product: String::from("sdk_gphone64_x86_64"),
+            model: String::from("sdk_gphone64_x86_64"),
+            device: String::from("emu64xa"),
+            usb: None,
+            features: String::new(),
+            shell: HashMap::new(),
+            files: BTreeMap::new(),
+            dirs: BTreeMap::new(),
+        }
+    }
+
+    pub fn state(mut self, state: DeviceState) -> MockDevice {
+        self.state = state;
+        self
+    }
+
+    pub fn product(mut self, product: &str) -> MockDevice {
+        self.product = product.to_string();
+        self
+    }
+
+    pub fn model(mut self, model: &str) -> MockDevice {
+        self.model = model.to_string();
+        self
+    }
+
+    pub fn device(mut self, device: &str) -> MockDevice {
+        self.device = device.to_string();
+        self
+    }
+
+    /// Make it a USB device at `devpath`, e.g. "1-1.2"; devices are local (TCP/IP) by default.
+    pub fn usb(mut self, devpath: &str) -> MockDevice {
+        self.usb = Some(devpath.to_string());
+        self
+    }
+
+    /// The comma separated feature list. Only the v1 sync requests are served, so leave out
+    /// `stat_v2`, `ls_v2` and `sendrecv_v2*`.
+    pub fn features(mut self, features: &str) -> MockDevice {
+        self.features = features.to_string();
+        self
+    }
+
+    /// Answer the shell command `cmd` with `stdout` and exit code 0.
+    pub fn shell(self, cmd: &str, stdout: &str) -> MockDevice {
+        self.shell_output(
+            cmd,
+            ShellOutput {
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: Vec::new(),
+                exit_code: 0,
+            },
+        )
+    }
+
+    pub fn shell_output(mut self, cmd: &str, output: ShellOutput) -> MockDevice {
+        self.shell.insert(cmd.to_string(), output);
+        self
+    }
+
+    /// A regular file with mode 0644, its parent directories exist implicitly.
+    pub fn file(self, path: &str, data: &[u8]) -> MockDevice {
+        self.file_with_mode(path, data, 0o644)
+    }
+
+    pub fn file_with_mode(mut self, path: &str, data: &[u8], mode: u32) -> MockDevice {
+        self.files.insert(
+            path.to_string(),
+            MockFile {
+                data: data.to_vec(),
+                mode: S_IFREG | (mode & 0o7777),
+                mtime: 0,
+            },
+        );
+        self
+    }
+
+    /// A directory with mode 0755, e.g. to serve an empty one.
+    pub fn dir(mut self, path: &str) -> MockDevice {
+        self.dirs.insert(path.to_string(), S_IFDIR | 0o755);
+        self
+    }
+
+    pub fn symlink(mut self, path: &str, target: &str) -> MockDevice {
+        self.files.insert(
+            path.to_string(),
+            MockFile {
+                data: target.as_bytes().to_vec(),
+                mode: S_IFLNK | 0o777,
+                mtime: 0,
+            },
+        );
+        self
+    }
+
+    fn matches(&self, transport_id: u32, selector: &TransportSelector) -> bool {
+        match selector {
+            TransportSelector::Serial(serial) => &self.serial == serial,
+            TransportSelector::TransportId(id) => *id == transport_id,
+            TransportSelector::Any => true,
+            TransportSelector::Usb => self.usb.is_some(),
+            TransportSelector::Local => self.usb.is_none(),
+        }
+    }
+
+    fn run(&mut self, cmd: &str) -> ShellOutput {
+        if let Some(output) = self.shell.get(cmd) {
+            return output.clone();
+        }
+        let args = split_args(cmd).unwrap_or_default();
+        let args = args.iter().map(String::as_str).collect::<Vec<&str>>();
+        let res = match args[..] {
+            ["mkdir", "-p", ref paths @ ..] if !paths.is_empty() => self.mkdir(paths),
+            ["chmod", mode, ref paths @ ..] if !paths.is_empty() => {
+                match u32::from_str_radix(mode, 8) {
+                    Ok(mode) => self.chmod(mode, paths),
+                    Err(_) => Err(format!("chmod: bad mode '{}'", mode)),
+                }
+            }
+            ["readlink", path] => match self.files.get(path) {
+                Some(file) if file.mode & S_IFMT == S_IFLNK => {
+                    Ok(format!("{}\n", String::from_utf8_lossy(&file.data)))
+                }
+                _ => Err(String::new()),
+            },
+            _ => {
+                return ShellOutput {
+                    stdout: Vec::new(),
+                    stderr: format!(
+                        "/system/bin/sh: {}: inaccessible or not found\n",
+                        cmd.split_whitespace().next().unwrap_or_default()
+                    )
+                    .into_bytes(),
+                    exit_code: 127,
+                }
+            }
+        };
+        match res {
+            Ok(stdout) => ShellOutput {
+                stdout: stdout.into_bytes(),
+                stderr: Vec::new(),
+                exit_code: 0,
+            },
+            Err(stderr) => ShellOutput {
+                stdout: Vec::new(),
+                stderr: stderr.into_bytes(),
+                exit_code: 1,
+            },
+        }
+    }
+
+    fn mkdir(&mut self, paths: &[&str]) -> Result<String, String> {
+        for path in paths {
+            let path = path.trim_end_matches('/');
+            match self.stat(path) {
+                Some((mode, _, _)) if mode & S_IFMT == S_IFDIR => {}
+                Some(_) => return Err(format!("mkdir: '{}': File exists\n", path)),
+                None => {
+                    self.dirs.insert(path.to_string(), S_IFDIR | 0o755);
+                }
+            }
+        }
+        Ok(String::new())
+    }
+
+    fn chmod(&mut self, mode: u32, paths: &[&str]) -> Result<String, String> {
+        for path in paths {
+            let path = path.trim_end_matches('/');
+            if let Some(file) = self.files.get_mut(path) {
+                file.mode = (file.mode & S_IFMT) | (mode & 0o7777);
+            } else if self.stat(path).is_some() {
+                self.dirs
+                    .insert(path.to_string(), S_IFDIR | (mode & 0o7777));
+            } else {
+                return Err(format!("chmod: {}: No such file or directory\n", path));
+            }
+        }
+        Ok(String::new())
+    }
+
+    // adbd creates missing parents, but not inside a directory it may not write to
+    fn writable(&self, path: &str) -> bool {
+        let parent = path
+            .rsplit_once('/')
+            .map(|(parent, _)| parent)
+            .unwrap_or("");
+        self.dirs.get(parent).is_none_or(|mode| mode & 0o200 != 0)
+    }
+
+    // (mode, size, mtime), `None` when the path doesn't exist
+    fn stat(&self, path: &str) -> Option<(u32, u32, u32)> {
+        let trimmed = path.trim_end_matches('/');
+        if let Some(file) = self.files.get(trimmed) {
+            // a trailing slash only resolves directories
+            if trimmed.len() == path.len() {
+                return Some((file.mode, file.data.len() as u32, file.mtime));
+            }
+            return None;
+        }
+        if let Some(mode) = self.dirs.get(trimmed) {
+            return Some((*mode, 4096, 0));
+        }
+        let prefix = format!("{}/", trimmed);
+        if self.paths().any(|(key, _)| key.starts_with(&prefix)) {
+            return Some((S_IFDIR | 0o755, 4096, 0));
+        }
+        None
+    }
+
+    // the direct children of a directory with their stat
+    fn list(&self, path: &str) -> BTreeMap<String, (u32, u32, u32)> {
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        let mut entries = BTreeMap::new();
+        for (key, stat) in self.paths() {
+            let rest = match key.strip_prefix(&prefix) {
+                Some(rest) => rest,
+                None => continue,
+            };
+            match rest.split_once('/') {
+                Some((dir, _)) => {
+                    let stat = self.stat(&format!("{}{}", prefix, dir));
+                    entries.insert(dir.to_string(), stat.unwrap_or((S_IFDIR | 0o755, 4096, 0)))
+                }
+                None => entries.insert(rest.to_string(), stat),
+            };
+        }
+        entries
+    }
+
+    // every file and explicit directory with its stat
+    fn paths(&self) -> impl Iterator<Item = (&String, (u32, u32, u32))> {
+        let files = self
+            .files
+            .iter()
+            .map(|(path, file)| (path, (file.mode, file.data.len() as u32, file.mtime)));
+        let dirs = self
+            .dirs
+            .iter()
+            .map(|(path, mode)| (path, (*mode, 4096, 0)));
+        files.chain(dirs)
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    devices: Vec<(u32, MockDevice)>,
+    next_transport_id: u32,
+    next_port: u16,
+    forwards: Vec<ForWardItem>,
+    requests: Vec<String>,
+}
+
+impl State {
+    // a device a request may be routed to, or the FAIL message explaining why not
+    fn resolve(&self, selector: &TransportSelector) -> Result<(u32, MockDevice), String> {
+        let mut matches = self
+            .devices
+            .iter()
+            .filter(|(id, device)| device.matches(*id, selector));
+        let found = match (matches.next(), matches.next()) {
+            (Some(found), None) => found.clone(),
+            (Some(_), Some(_)) => {
+                return Err(match selector {
+                    TransportSelector::Local => String::from("more than one emulator"),
+                    TransportSelector::Usb => String::from("more than one device"),
+                    _ => String::from("more than one device/emulator"),
+                })
+            }
+            (None, _) => {
+                return Err(match selector {
+                    TransportSelector::Serial(serial) => format!("device '{}' not found", serial),
+                    TransportSelector::TransportId(id) => {
+                        format!("no device with transport id '{}'", id)
+                    }
+                    TransportSelector::Any => String::from("no devices/emulators found"),
+                    TransportSelector::Usb => String::from("no devices found"),
+                    TransportSelector::Local => String::from("no emulators found"),
+                })
+            }
+        };
+        Ok(found)
+    }
+
+    // like `resolve`, but the device also has to be usable for services
+    fn resolve_online(&self, selector: &TransportSelector) -> Result<(u32, MockDevice), String> {
+        let (id, device) = self.resolve(selector)?;
+        match device.state {
+            DeviceState::Offline | DeviceState::Connecting => Err(String::from("device offline")),
+            DeviceState::Unauthorized | DeviceState::Authorizing => Err(String::from(
+                "device unauthorized.\nThis adb server's $ADB_VENDOR_KEYS is not set",
+            )),
+            DeviceState::NoPermissions => Err(String::from(
+                "insufficient permissions for device: user in plugdev group; are your udev rules wrong?",
+            )),
+            _ => Ok((id, device)),
+        }
+    }
+
+    fn device_mut(&mut self, serial: &str) -> Option<&mut MockDevice> {
+        self.devices
+            .iter_mut()
+            .map(|(_, device)| device)
+            .find(|device| device.serial == serial)
+    }
+
+    fn add(&mut self, device: MockDevice) -> u32 {
+        self.next_transport_id += 1;
+        self.devices.push((self.next_transport_id, device));
+        self.next_transport_id
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    stopped: AtomicBool,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // a panicking handler must not take the other tests down with it
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// An in-process adb server on a random local port speaking the smart-socket protocol,
+/// for testing `AdbClient` without adb or hardware. Every connection is served by its own
+/// thread; the server stops on `host:kill` or when dropped.
+#[derive(Debug)]
+pub struct MockServer {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    accept_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MockServer {
+    pub fn start() -> io::Result<MockServer> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared::default());
+        shared.lock().next_port = FIRST_FORWARD_PORT;
+        let accept_shared = shared.clone();
+        let accept_thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if accept_shared.stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let shared = accept_shared.clone();
+                    thread::spawn(move || {
+                        if let Err(error) = handle(stream, &shared) {
+                            log::debug!("mock adb server: {}", error);
+                        }
+                    });
+                }
+            }
+        });
+        Ok(MockServer {
+            addr,
+            shared,
+            accept_thread: Some(accept_thread),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// A client talking to this server.
+    pub fn client(&self) -> AdbClient {
+        AdbClient::new(
+            self.addr.ip().to_string(),
+            self.addr.port() as u32,
+            time::Duration::from_secs(10),
+        )
+    }
+
+    /// Plug in `device`, returns its transport id.
+    pub fn add_device(&self, device: MockDevice) -> u32 {
+        self.shared.lock().add(device)
+    }
+
+    pub fn remove_device(&self, serial: &str) {
+        self.shared
+            .lock()
+            .devices
+            .retain(|(_, device)| device.serial != serial);
+    }
+
+    pub fn set_state(&self, serial: &str, state: DeviceState) {
+        if let Some(device) = self.shared.lock().device_mut(serial) {
+            device.state = state;
+        }
+    }
+
+    /// A file of the device's tree, e.g. to check what was pushed.
+    pub fn file(&self, serial: &str, path: &str) -> Option<MockFile> {
+        self.shared
+            .lock()
+            .device_mut(serial)
+            .and_then(|device| device.files.get(path).cloned())
+    }
+
+    pub fn forwards(&self) -> Vec<ForWardItem> {
+        self.shared.lock().forwards.clone()
+    }
+
+    /// Every request received so far, without the length prefix.
+    pub fn requests(&self) -> Vec<String> {
+        self.shared.lock().requests.clone()
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.shared.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Stop accepting connections and release the port.
+    pub fn stop(&mut self) {
+        stop(&self.shared, self.addr);
+        if let Some(accept_thread) = self.accept_thread.take() {
+            let _ = accept_thread.join();
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn stop(shared: &Shared, addr: SocketAddr) {
+    if !shared.stopped.swap(true, Ordering::SeqCst) {
+        // wake up the accept loop so it sees the flag and drops the listener
+        let _ = TcpStream::connect(addr);
+    }
+}
+
+fn read_request(stream: &mut TcpStream) -> io::Result<String> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+    let length = std::str::from_utf8(&length)
+        .ok()
+        .and_then(|length| usize::from_str_radix(length, 16).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid request length"))?;
+    let mut request = vec![0; length];
+    stream.read_exact(&mut request)?;
+    Ok(String::from_utf8_lossy(&request).to_string())
+}
+
+fn write_block(stream: &mut TcpStream, data: &[u8]) -> io::Result<()> {
+    stream.write_all(format!("{:04x}", data.len()).as_bytes())?;
+    stream.write_all(data)
+}
+
+fn okay(stream: &mut TcpStream) -> io::Result<()> {
+    stream.write_all(b"OKAY")
+}
+
+fn fail(stream: &mut TcpStream, message: &str) -> io::Result<()> {
+    stream.write_all(b"FAIL")?;
+    write_block(stream, message.as_bytes())
+}
+
+// split "host-serial:<serial>:<command>" and friends, the serial itself may contain colons
+fn parse_host_request(state: &State, request: &str) -> Option<(TransportSelector, String)> {
+    if let Some(command) = request.strip_prefix("host:") {
+        return Some((TransportSelector::Any, command.to_string()));
+    }
+    if let Some(command) = request.strip_prefix("host-usb:") {
+        return Some((TransportSelector::Usb, command.to_string()));
+    }
+    if let Some(command) = request.strip_prefix("host-local:") {
+        return Some((TransportSelector::Local, command.to_string()));
+    }
+    if let Some(rest) = request.strip_prefix("host-transport-id:") {
+        let (id, command) = rest.split_once(':')?;
+        return Some((
+            TransportSelector::TransportId(id.parse().ok()?),
+            command.to_string(),
+        ));
+    }
+    let rest = request.strip_prefix("host-serial:")?;
+    let known = state
+        .devices
+        .iter()
+        .map(|(_, device)| device.serial.as_str())
+        .filter(|serial| rest.starts_with(&format!("{}:", serial)))
+        .max_by_key(|serial| serial.len());
+    let (serial, command) = match known {
+        Some(serial) => (serial, &rest[serial.len() + 1..]),
+        None => rest.split_once(':')?,
+    };
+    Some((
+        TransportSelector::Serial(serial.to_string()),
+        command.to_string(),
+    ))
+}
+
+fn handle(mut stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    let request = read_request(&mut stream)?;
+    let parsed = {
+        let mut state = shared.lock();
+        state.requests.push(request.clone());
+        parse_host_request(&state, &request)
+    };
+    let (selector, command) = match parsed {
+        Some(parsed) => parsed,
+        None => return fail(&mut stream, &format!("unknown host service '{}'", request)),
+    };
+    // "host:" requests about forwards cover every device, the others pick one
+    let all_devices = request.starts_with("host:");
+
+    match command.as_str() {
+        "version" => {
+            okay(&mut stream)?;
+            write_block(&mut stream, format!("{:04x}", SERVER_VERSION).as_bytes())
+        }
+        "kill" => {
+            okay(&mut stream)?;
+            stop(shared, stream.local_addr()?);
+            Ok(())
+        }
+        "devices" | "devices-l" => {
+            let state = shared.lock();
+            let mut output = String::new();
+            for (id, device) in state.devices.iter() {
+                if command == "devices" {
+                    output += &format!("{}\t{}\n", device.serial, device.state);
+                    continue;
+                }
+                output += &format!("{:<22} {}", device.serial, device.state);
+                if let Some(usb) = &device.usb {
+                    output += &format!(" usb:{}", usb);
+                }
+                output += &format!(
+                    " product:{} model:{} device:{} transport_id:{}\n",
+                    device.product, device.model, device.device, id
+                );
+            }
+            drop(state);
+            okay(&mut stream)?;
+            write_block(&mut stream, output.as_bytes())
+        }
+        "features" | "get-state" | "get-serialno" | "get-devpath" => {
+            let resolved = shared.lock().resolve(&selector);
+            let device = match resolved {
+                Ok((_, device)) => device,
+                Err(message) => return fail(&mut stream, &message),
+            };
+            let answer = match command.as_str() {
+                "features" => device.features.clone(),
+                "get-state" => device.state.to_string(),
+                "get-serialno" => device.serial.clone(),
+                _ => device
+                    .usb
+                    .clone()
+                    .unwrap_or_else(|| String::from("unknown")),
+            };
+            okay(&mut stream)?;
+            write_block(&mut stream, answer.as_bytes())
+        }
+        "list-forward" => {
+            let state = shared.lock();
+            let serial = if all_devices {
+                None
+            } else {
+                match state.resolve(&selector) {
+                    Ok((_, device)) => Some(device.serial),
+                    Err(message) => return fail(&mut stream, &message),
+                }
+            };
+            let output = state
+                .forwards
+                .iter()
+                .filter(|item| serial.is_none() || serial.as_ref() == Some(&item.serial))
+                .map(|item| format!("{} {} {}\n", item.serial, item.local, item.remote))
+                .collect::<String>();
+            drop(state);
+            okay(&mut stream)?;
+            write_block(&mut stream, output.as_bytes())
+        }
+        "killforward-all" => {
+            let mut state = shared.lock();
+            if all_devices {
+                state.forwards.clear();
+            } else {
+                match state.resolve(&selector) {
+                    Ok((_, device)) => state.forwards.retain(|item| item.serial != device.serial),
+                    Err(message) => return fail(&mut stream, &message),
+                }
+            }
+            drop(state);
+            stream.write_all(b"OKAYOKAY")
+        }
+        _ => {
+            if let Some(local) = command.strip_prefix("killforward:") {
+                let mut state = shared.lock();
+                // like adb, a single forward is only removed through a device transport
+                if let Err(message) = state.resolve(&selector) {
+                    drop(state);
+                    return fail(&mut stream, &message);
+                }
+                let before = state.forwards.len();
+                state.forwards.retain(|item| item.local != local);
+                let removed = state.forwards.len() != before;
+                drop(state);
+                if !removed {
+                    return fail(&mut stream, &format!("listener '{}' not found", local));
+                }
+                return stream.write_all(b"OKAYOKAY");
+            }
+            if let Some(spec) = command.strip_prefix("forward:") {
+                return forward(&mut stream, shared, &selector, spec);
+            }
+            if let Some(condition) = command.strip_prefix("wait-for-") {
+                return wait_for(&mut stream, shared, &selector, condition);
+            }
+            if let Some(addr) = command.strip_prefix("connect:") {
+                let mut state = shared.lock();
+                let message = if state.device_mut(addr).is_some() {
+                    format!("already connected to {}", addr)
+                } else {
+                    state.add(MockDevice::new(addr));
+                    format!("connected to {}", addr)
+                };
+                drop(state);
+                okay(&mut stream)?;
+                return write_block(&mut stream, message.as_bytes());
+            }
+            if let Some(addr) = command.strip_prefix("disconnect:") {
+                let mut state = shared.lock();
+                if state.device_mut(addr).is_none() {
+                    drop(state);
+                    return fail(&mut stream, &format!("no such device '{}'", addr));
+                }
+                state.devices.retain(|(_, device)| device.serial != addr);
+                drop(state);
+                okay(&mut stream)?;
+                return write_block(&mut stream, format!("disconnected {}", addr).as_bytes());
+            }
+            if let Some(selector) = transport_selector(&command) {
+                let resolved = shared.lock().resolve_online(&selector);
+                let (id, _) = match resolved {
+                    Ok(resolved) => resolved,
+                    Err(message) => return fail(&mut stream, &message),
+                };
+                okay(&mut stream)?;
+                return serve_device(&mut stream, shared, id);
+            }
+            fail(&mut stream, &format!("unknown host service '{}'", command))
+        }
+    }
+}
+
+fn transport_selector(command: &str) -> Option<TransportSelector> {
+    match command {
+        "transport-any" => Some(TransportSelector::Any),
+        "transport-usb" => Some(TransportSelector::Usb),
+        "transport-local" => Some(TransportSelector::Local),
+        _ => {
+            if let Some(id) = command.strip_prefix("transport-id:") {
+                return id.parse().ok().map(TransportSelector::TransportId);
+            }
+            command
+                .strip_prefix("transport:")
+                .map(|serial| TransportSelector::Serial(serial.to_string()))
+        }
+    }
+}
+
+fn forward(
+    stream: &mut TcpStream,
+    shared: &Shared,
+    selector: &TransportSelector,
+    spec: &str,
+) -> io::Result<()> {
+    let (norebind, spec) = match spec.strip_prefix("norebind:") {
+        Some(spec) => (true, spec),
+        None => (false, spec),
+    };
+    let (local, remote) = match spec.split_once(';') {
+        Some(parts) => parts,
+        None => return fail(stream, &format!("malformed forward spec '{}'", spec)),
+    };
+    let mut state = shared.lock();
+    let serial = match state.resolve_online(selector) {
+        Ok((_, device)) => device.serial,
+        Err(message) => return fail(stream, &message),
+    };
+    let allocated = if local == "tcp:0" {
+        state.next_port += 1;
+        Some(state.next_port)
+    } else {
+        None
+    };
+    let local = match allocated {
+        Some(port) => format!("tcp:{}", port),
+        None => local.to_string(),
+    };
+    if let Some(existing) = state.forwards.iter_mut().find(|item| item.local == local) {
+        if norebind {
+            drop(state);
+            return fail(stream, "cannot rebind existing socket");
+        }
+        existing.serial = serial;
+        existing.remote = remote.to_string();
+    } else {
+        state.forwards.push(ForWardItem {
+            serial,
+            local,
+            remote: remote.to_string(),
+        });
+    }
+    drop(state);
+    stream.write_all(b"OKAYOKAY")?;
+    match allocated {
+        Some(port) => write_block(stream, port.to_string().as_bytes()),
+        None => Ok(()),
+    }
+}
+
+// "<transport>-<state>", the second OKAY is sent once a matching device is in that state
+fn wait_for(
+    stream: &mut TcpStream,
+    shared: &Shared,
+    selector: &TransportSelector,
+    condition: &str,
+) -> io::Result<()> {
+    let (transport, wanted) = match condition.split_once('-') {
+        Some(parts) => parts,
+        None => return fail(stream, &format!("bad wait-for request '{}'", condition)),
+    };
+    let transport = match transport {
+        "usb" => TransportSelector::Usb,
+        "local" => TransportSelector::Local,
+        _ => TransportSelector::Any,
+    };
+    okay(stream)?;
+    while !shared.stopped.load(Ordering::SeqCst) {
+        let matching = shared
+            .lock()
+            .devices
+            .iter()
+            .filter(|(id, device)| device.matches(*id, selector) && device.matches(*id, &transport))
+            .map(|(_, device)| device.state.to_string())
+            .collect::<Vec<String>>();
+        let reached = if wanted == "disconnect" {
+            matching.is_empty()
+        } else {
+            matching.iter().any(|state| state == wanted)
+        };
+        if reached {
+            return okay(stream);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+// the services of a connection switched over to the device `transport_id`
+fn serve_device(stream: &mut TcpStream, shared: &Shared, transport_id: u32) -> io::Result<()> {
+    let service = read_request(stream)?;
+    let device = {
+        let mut state = shared.lock();
+        state.requests.push(service.clone());
+        state
+            .devices
+            .iter()
+            .find(|(id, _)| *id == transport_id)
+            .map(|(_, device)| device.clone())
+    };
+    if device.is_none() {
+        return fail(stream, "device offline");
+    }
+
+    if let Some(cmd) = service.strip_prefix("shell:") {
+        okay(stream)?;
+        // the shell v1 fallback of `shell_v2` appends the exit code to stdout
+        if let Some(cmd) = cmd.strip_suffix("; echo :$?") {
+            let output = run(shared, transport_id, cmd);
+            stream.write_all(&output.stdout)?;
+            stream.write_all(&output.stderr)?;
+            return stream.write_all(format!(":{}\n", output.exit_code).as_bytes());
+        }
+        let output = run(shared, transport_id, cmd);
+        stream.write_all(&output.stdout)?;
+        return stream.write_all(&output.stderr);
+    }
+    if service.starts_with("shell,") {
+        let cmd = service
+            .split_once(':')
+            .map(|(_, cmd)| cmd)
+            .unwrap_or_default();
+        okay(stream)?;
+        let output = if cmd == "cat" {
+            cat(stream)?
+        } else {
+            run(shared, transport_id, cmd)
+        };
+        for (id, data) in [
+            (shell::ID_STDOUT, output.stdout),
+            (shell::ID_STDERR, output.stderr),
+            (shell::ID_EXIT, vec![output.exit_code as u8]),
+        ] {
+            if data.is_empty() {
+                continue;
+            }
+            stream.write_all(&[id])?;
+            stream.write_all(&(data.len() as u32).to_le_bytes())?;
+            stream.write_all(&data)?;
+        }
+        // closing with the client's CLOSE_STDIN still unread would reset the connection
+        stream.shutdown(Shutdown::Write)?;
+        return io::copy(stream, &mut io::sink()).map(|_| ());
+    }
+    if let Some(cmd) = service.strip_prefix("exec:") {
+        okay(stream)?;
+        return stream.write_all(&run(shared, transport_id, cmd).stdout);
+    }
+    if service == "sync:" {
+        okay(stream)?;
+        return serve_sync(stream, shared, transport_id);
+    }
+    fail(stream, &format!("unknown service '{}'", service))
+}
+
+// `cat` over shell v2: echo stdin once the client closed it
+fn cat(stream: &mut TcpStream) -> io::Result<ShellOutput> {
+    let mut output = ShellOutput::default();
+    loop {
+        let mut header = [0; 5];
+        stream.read_exact(&mut header)?;
+        let mut data =
+            vec![0; u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize];
+        stream.read_exact(&mut data)?;
+        match header[0] {
+            shell::ID_STDIN => output.stdout.extend_from_slice(&data),
+            shell::ID_CLOSE_STDIN => return Ok(output),
+            _ => {}
+        }
+    }
+}
+
+// the shell commands change the tree, so they run on the shared device and not a copy
+fn run(shared: &Shared, transport_id: u32, cmd: &str) -> ShellOutput {
+    match shared
+        .lock()
+        .devices
+        .iter_mut()
+        .find(|(id, _)| *id == transport_id)
+    {
+        Some((_, device)) => device.run(cmd),
+        None => ShellOutput {
+            stdout: Vec::new(),
+            stderr: b"device offline\n".to_vec(),
+            exit_code: 1,
+        },
+    }
+}
+
+// split a command line the way `sh` does for plain words and quoted strings
+fn split_args(cmd: &str) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut chars = cmd.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => arg.push(c),
+                    }
+                }
+            }
+            '\\' => arg.get_or_insert_with(String::new).push(chars.next()?),
+            c if c.is_whitespace() => args.extend(arg.take()),
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    Some(args)
+}
+
+fn read_sync_header(stream: &mut TcpStream) -> io::Result<([u8; 4], u32)> {
+    let mut header = [0; 8];
+    stream.read_exact(&mut header)?;
+    let mut id = [0; 4];
+    id.copy_from_slice(&header[..4]);
+    Ok((
+        id,
+        u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+    ))
+}
+
+fn write_sync(stream: &mut TcpStream, id: &[u8; 4], values: &[u32]) -> io::Result<()> {
+    stream.write_all(id)?;
+    for value in values {
+        stream.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn sync_fail(stream: &mut TcpStream, message: &str) -> io::Result<()> {
+    write_sync(stream, b"FAIL", &[message.len() as u32])?;
+    stream.write_all(message.as_bytes())
+}
+
+// the v1 sync requests: STAT, LIST, RECV and SEND, until QUIT or the client hangs up
+fn serve_sync(stream: &mut TcpStream, shared: &Shared, transport_id: u32) -> io::Result<()> {
+    let device = |shared: &Shared| {
+        shared
+            .lock()
+            .devices
+            .iter()
+            .find(|(id, _)| *id == transport_id)
+            .map(|(_, device)| device.clone())
+            .unwrap_or_else(|| MockDevice::new(""))
+    };
+    loop {
+        let (id, length) = match read_sync_header(stream) {
+            Ok(header) => header,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        let mut path = vec![0; length as usize];
+        stream.read_exact(&mut path)?;
+        let path = String::from_utf8_lossy(&path).to_string();
+        match &id {
+            b"STAT" => {
+                let (mode, size, mtime) = device(shared).stat(&path).unwrap_or((0, 0, 0));
+                write_sync(stream, b"STAT", &[mode, size, mtime])?;
+            }
+            b"LIST" => {
+                for (name, (mode, size, mtime)) in device(shared).list(&path) {
+                    write_sync(stream, b"DENT", &[mode, size, mtime, name.len() as u32])?;
+                    stream.write_all(name.as_bytes())?;
+                }
+                write_sync(stream, b"DONE", &[0, 0, 0, 0])?;
+            }
+            b"RECV" => match device(shared).files.get(&path) {
+                Some(file) => {
+                    for chunk in file.data.chunks(SYNC_DATA_MAX) {
+                        write_sync(stream, b"DATA", &[chunk.len() as u32])?;
+                        stream.write_all(chunk)?;
+                    }
+                    write_sync(stream, b"DONE", &[0])?;
+                }
+                None => sync_fail(stream, "No such file or directory")?,
+            },
+            b"SEND" => {
+                let (path, mode) = match path.rsplit_once(',') {
+                    Some((path, mode)) => (path.to_string(), mode.parse().unwrap_or(S_IFREG)),
+                    None => (path.clone(), S_IFREG | 0o644),
+                };
+                let mut data = Vec::new();
+                loop {
+                    match read_sync_header(stream)? {
+                        (id, length) if &id == b"DATA" => {
+                            let mut chunk = vec![0; length as usize];
+                            stream.read_exact(&mut chunk)?;
+                            data.extend_from_slice(&chunk);
+                        }
+                        (id, _) if &id == b"DONE" && !device(shared).writable(&path) => {
+                            return sync_fail(stream, &format!("{}: Permission denied", path));
+                        }
+                        (id, mtime) if &id == b"DONE" => {
+                            if let Some((_, device)) = shared
+                                .lock()
+                                .devices
+                                .iter_mut()
+                                .find(|(id, _)| *id == transport_id)
+                            {
+                                device.files.insert(path, MockFile { data, mode, mtime });
+                            }
+                            break;
+                        }
+                        _ => return sync_fail(stream, "expected DATA or DONE"),
+                    }
+                }
+                write_sync(stream, b"OKAY", &[0])?;
+            }
+            b"QUIT" => return Ok(()),
+            _ => {
+                return sync_fail(
+                    stream,
+                    &format!("unsupported sync request {}", String::from_utf8_lossy(&id)),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MockDevice, MockServer, SERVER_VERSION};
+    use crate::device::AdbDevice;
+    use crate::error::AdbError;
+    use crate::proto::{DeviceState, ForwardSpec, ShellOutput, TransportSelector};
+    use crate::server::AdbServer;
+    use std::io::Read;
+
+    #[test]
+    fn test_host_requests() {
+        let server = MockServer::start().unwrap();
+        server.add_device(MockDevice::new("emulator-5554").model("Pixel_7"));
+        server.add_device(
+            MockDevice::new("R58M")
+                .usb("1-1.2")
+                .state(DeviceState::Unauthorized),
+        );
+        let adb = server.client();
+        assert_eq!(adb.server_version().unwrap(), SERVER_VERSION);
+
+        let devices = adb.list_devices().unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].model.as_deref(), Some("Pixel_7"));
+        assert_eq!(devices[1].usb.as_deref(), Some("1-1.2"));
+        assert_eq!(devices[1].transport_id, Some(2));
+        assert_eq!(adb.devices_list().unwrap().len(), 1);
+
+        // the unauthorized device doesn't count
+        let device = adb.device(TransportSelector::Any).unwrap();
+        assert_eq!(device.shell_mixin.serial, "emulator-5554");
+        if std::env::var_os("ANDROID_SERIAL").is_none() {
+            let device = adb.default_device().unwrap();
+            assert_eq!(device.shell_mixin.serial, "emulator-5554");
+        }
+        match adb.transport(&TransportSelector::Usb) {
+            Err(AdbError::Unauthorized) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match adb.transport(&TransportSelector::Serial(String::from("missing"))) {
+            Err(AdbError::DeviceNotFound { serial }) => assert_eq!(serial, "missing"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        assert_eq!(
+            adb.connect("192.168.1.20:5555").unwrap(),
+            "connected to 192.168.1.20:5555"
+        );
+        let device = adb
+            .device(TransportSelector::Serial(String::from("192.168.1.20:5555")))
+            .unwrap();
+        assert_eq!(device.get_serial_no().unwrap(), "192.168.1.20:5555");
+        assert_eq!(device.get_state().unwrap(), "device");
+    }
+
+    #[test]
+    fn test_shell() {
+        let server = MockServer::start().unwrap();
+        server.add_device(
+            MockDevice::new("emulator-5554")
+                .features("shell_v2,cmd")
+                .shell("getprop ro.product.model", "Pixel 7\n")
+                .shell_output(
+                    "ls /data",
+                    ShellOutput {
+                        stdout: Vec::new(),
+                        stderr: b"ls: /data: Permission denied\n".to_vec(),
+                        exit_code: 1,
+                    },
+                ),
+        );
+        let device = server.client().device(TransportSelector::Any).unwrap();
+        assert_eq!(
+            device.shell_mixin.get_prop("ro.product.model").unwrap(),
+            "Pixel 7"
+        );
+        let output = device.shell_v2("ls /data").unwrap();
+        assert_eq!(output.exit_code, 1);
+        assert_eq!(output.stderr_str(), "ls: /data: Permission denied\n");
+        assert_eq!(output.stdout, b"");
+        assert_eq!(device.shell_v2("reboot").unwrap().exit_code, 127);
+        // only exits once stdin is closed
+        let output = device.shell_v2("cat").unwrap();
+        assert_eq!((output.stdout.len(), output.exit_code), (0, 0));
+
+        // without shell_v2 the exit code comes from the v1 fallback
+        server.add_device(MockDevice::new("emulator-5556").shell("id -u", "2000\n"));
+        let device = AdbDevice::new(crate::device::ShellMixin::new(
+            server.client(),
+            String::from("emulator-5556"),
+            0,
+            None,
+        ));
+        let output = device.shell_v2("id -u").unwrap();
+        assert_eq!(
+            (output.stdout_str().as_str(), output.exit_code),
+            ("2000\n", 0)
+        );
+        assert_eq!(device.adb_out("id -u").unwrap(), b"2000\n");
+    }
+
+    #[test]
+    fn test_sync() {
+        let server = MockServer::start().unwrap();
+        server.add_device(
+            MockDevice::new("emulator-5554")
+                .file("/sdcard/a.txt", b"hello")
+                .file_with_mode("/sdcard/bin/run.sh", b"#!/bin/sh\n", 0o755),
+        );
+        let device = server.client().device(TransportSelector::Any).unwrap();
+        let sync = device.sync().unwrap();
+
+        let info = sync.stat("/sdcard/a.txt").unwrap();
+        assert!(info.is_file());
+        assert_eq!(info.size, 5);
+        assert!(sync.stat("/sdcard/").unwrap().is_dir());
+        assert_eq!(sync.stat("/sdcard/missing").unwrap().mode, 0);
+
+        let names = sync
+            .list("/sdcard")
+            .unwrap()
+            .map(|entry| entry.unwrap().path)
+            .collect::<Vec<String>>();
+        assert_eq!(names, vec!["a.txt", "bin"]);
+
+        let mut data = Vec::new();
+        sync.pull("/sdcard/a.txt", &mut data).unwrap();
+        assert_eq!(data, b"hello");
+        assert!(matches!(
+            sync.pull("/sdcard/missing", &mut Vec::new()),
+            Err(AdbError::SyncError { .. })
+        ));
+
+        let content = vec![7u8; 100 * 1024];
+        sync.push(&mut &content[..], "/data/local/tmp/blob", 0o600, 1700000000)
+            .unwrap();
+        let pushed = server
+            .file("emulator-5554", "/data/local/tmp/blob")
+            .unwrap();
+        assert_eq!(pushed.data, content);
+        assert_eq!(pushed.mode & 0o7777, 0o600);
+        assert_eq!(pushed.mtime, 1700000000);
+    }
+
+    #[test]
+    fn test_forward() {
+        let server = MockServer::start().unwrap();
+        server.add_device(MockDevice::new("emulator-5554"));
+        let device = server.client().device(TransportSelector::Any).unwrap();
+        let port = device
+            .forward(
+                &ForwardSpec::Tcp(0),
+                &ForwardSpec::LocalAbstract(String::from("scrcpy")),
+                false,
+            )
+            .unwrap()
+            .unwrap();
+        device
+            .forward(&ForwardSpec::Tcp(8080), &ForwardSpec::Tcp(80), false)
+            .unwrap();
+        assert!(device
+            .forward(&ForwardSpec::Tcp(8080), &ForwardSpec::Tcp(81), true)
+            .is_err());
+
+        let forwards = device.forward_list().unwrap();
+        assert_eq!(forwards.len(), 2);
+        assert_eq!(forwards[0].local, format!("tcp:{}", port));
+        assert_eq!(forwards[0].remote, "localabstract:scrcpy");
+
+        device.forward_remove(&ForwardSpec::Tcp(8080)).unwrap();
+        assert!(device.forward_remove(&ForwardSpec::Tcp(8080)).is_err());
+        assert_eq!(server.forwards().len(), 1);
+        // without a transport the server has to pick the only device
+        server.add_device(MockDevice::new("emulator-5556"));
+        let mut conn = server.client()._connect().unwrap();
+        conn.send_command(&format!("host:killforward:tcp:{}", port))
+            .unwrap();
+        assert!(matches!(conn.check_oky(), Err(AdbError::MoreThanOneDevice)));
+        assert_eq!(server.forwards().len(), 1);
+        server.client().forward_remove_all().unwrap();
+        assert!(server.forwards().is_empty());
+    }
+
+    #[test]
+    fn test_kill() {
+        let server = MockServer::start().unwrap();
+        let mut conn = server.client()._connect().unwrap();
+        AdbServer::new(server.client()).kill().unwrap();
+        assert!(!server.is_running());
+        // connections accepted before the kill are still served
+        conn.send_command("host:version").unwrap();
+        conn.check_oky().unwrap();
+        let mut version = String::new();
+        conn.read_to_string(&mut version).unwrap();
+        assert_eq!(version, "00040029");
+    }
+}
@@ -0,0 +1,198 @@
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, SignatureScheme, StreamOwned};
+
+use crate::auth::AdbKey;
+use crate::error::AdbError;
+
+fn tls_error(error: impl std::error::Error + Send + Sync + 'static) -> AdbError {
+    AdbError::TlsError {
+        source: Box::new(error),
+    }
+}
+
+/// adbd presents a self-signed certificate; like adb itself we only authenticate the client.
+#[derive(Debug)]
+struct AcceptAnyServerCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// TLS 1.3 client config presenting a self-signed certificate of `key`, the device matches
+/// its public key against the keys it paired with.
+pub fn client_config(key: &AdbKey) -> Result<ClientConfig, AdbError> {
+    let key_der = PrivatePkcs8KeyDer::from(key.to_pkcs8_der()?);
+    let key_pair = rcgen::KeyPair::from_pkcs8_der_and_sign_algo(&key_der, &rcgen::PKCS_RSA_SHA256)
+        .map_err(tls_error)?;
+    let mut params = rcgen::CertificateParams::new(Vec::<String>::new()).map_err(tls_error)?;
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, "adbkey");
+    let cert = params.self_signed(&key_pair).map_err(tls_error)?;
+
+    let provider = Arc::new(ring::default_provider());
+    ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(tls_error)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyServerCert(provider)))
+        .with_client_auth_cert(
+            vec![cert.der().clone()],
+            PrivateKeyDer::Pkcs8(key_der.clone_key()),
+        )
+        .map_err(tls_error)
+}
+
+/// Run the TLS handshake on `sock` right away, so failures surface here and not on first use.
+pub fn connect<S: Read + Write>(
+    key: &AdbKey,
+    host: &str,
+    mut sock: S,
+) -> Result<StreamOwned<ClientConnection, S>, AdbError> {
+    // only used for SNI, the device certificate isn't checked against it
+    let server_name = ServerName::try_from(host.to_string())
+        .or_else(|_| ServerName::try_from("adbd"))
+        .map_err(tls_error)?;
+    let mut conn =
+        ClientConnection::new(Arc::new(client_config(key)?), server_name).map_err(tls_error)?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut sock).map_err(AdbError::from_read)?;
+    }
+    Ok(StreamOwned::new(conn, sock))
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::net::TcpStream;
+    use std::sync::Arc;
+
+    use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, UnixTime};
+    use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+    use rustls::{
+        DigitallySignedStruct, DistinguishedName, ServerConfig, ServerConnection, SignatureScheme,
+        StreamOwned,
+    };
+
+    // the device accepts any client certificate and checks its key afterwards
+    #[derive(Debug)]
+    struct AcceptAnyClientCert(Arc<CryptoProvider>);
+
+    impl ClientCertVerifier for AcceptAnyClientCert {
+        fn root_hint_subjects(&self) -> &[DistinguishedName] {
+            &[]
+        }
+
+        fn verify_client_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _now: UnixTime,
+        ) -> Result<ClientCertVerified, rustls::Error> {
+            Ok(ClientCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+            verify_tls12_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+            verify_tls13_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+
+    /// Server side of the TLS handshake, as done by adbd.
+    pub(crate) fn accept(mut sock: TcpStream) -> StreamOwned<ServerConnection, TcpStream> {
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec![String::from("adbd")])
+            .unwrap()
+            .self_signed(&key_pair)
+            .unwrap();
+        let provider = Arc::new(ring::default_provider());
+        let config = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_client_cert_verifier(Arc::new(AcceptAnyClientCert(provider)))
+            .with_single_cert(
+                vec![cert.der().clone()],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der())),
+            )
+            .unwrap();
+        let mut conn = ServerConnection::new(Arc::new(config)).unwrap();
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock).unwrap();
+        }
+        assert!(conn.peer_certificates().is_some());
+        StreamOwned::new(conn, sock)
+    }
+}
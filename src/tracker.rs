@@ -0,0 +1,163 @@
+use crate::client::AdbConnection;
+use crate::error::AdbError;
+use crate::proto::{DeviceEvent, DeviceEventKind, DeviceInfo};
+use std::collections::{BTreeMap, VecDeque};
+
+/// Follows `host:track-devices-l`, every snapshot sent by the server is diffed against the
+/// previous one and turned into `DeviceEvent`s. Created by `AdbClient::track_devices`.
+#[derive(Debug)]
+pub struct DeviceTracker {
+    conn: AdbConnection,
+    devices: BTreeMap<String, String>,
+    pending: VecDeque<DeviceEvent>,
+    done: bool,
+}
+
+impl DeviceTracker {
+    pub(crate) fn new(conn: AdbConnection) -> DeviceTracker {
+        DeviceTracker {
+            conn,
+            devices: BTreeMap::new(),
+            pending: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// The serial and state of every device in the last snapshot.
+    pub fn devices(&self) -> &BTreeMap<String, String> {
+        &self.devices
+    }
+
+    fn read_snapshot(&mut self) -> Result<(), AdbError> {
+        let output = self.conn.read_string_block()?;
+        let current = parse_snapshot(&output);
+        self.pending.extend(diff_devices(&self.devices, &current));
+        self.devices = current;
+        Ok(())
+    }
+}
+
+impl Iterator for DeviceTracker {
+    type Item = Result<DeviceEvent, AdbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() && !self.done {
+            if let Err(error) = self.read_snapshot() {
+                self.done = true;
+                return Some(Err(error));
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+// "serial    state product:x model:y device:z transport_id:1" for every device
+fn parse_snapshot(output: &str) -> BTreeMap<String, String> {
+    output
+        .lines()
+        .filter_map(DeviceInfo::parse)
+        .map(|info| (info.serial, info.state.to_string()))
+        .collect()
+}
+
+fn diff_devices(
+    previous: &BTreeMap<String, String>,
+    current: &BTreeMap<String, String>,
+) -> Vec<DeviceEvent> {
+    let mut events = Vec::new();
+    for serial in previous.keys() {
+        if !current.contains_key(serial) {
+            events.push(DeviceEvent {
+                present: false,
+                serial: serial.clone(),
+                status: String::from("absent"),
+                kind: DeviceEventKind::Disconnected,
+            });
+        }
+    }
+    for (serial, status) in current {
+        let kind = match previous.get(serial) {
+            None => DeviceEventKind::Connected,
+            Some(previous_status) if previous_status != status => DeviceEventKind::StateChanged,
+            Some(_) => continue,
+        };
+        events.push(DeviceEvent {
+            present: true,
+            serial: serial.clone(),
+            status: status.clone(),
+            kind,
+        });
+    }
+    events
+}
+
+#[cfg(test)]
+mod test {
+    use crate::client::AdbClient;
+    use crate::proto::DeviceEventKind;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time;
+
+    #[test]
+    fn test_track_devices() {
+        let snapshots = [
+            "",
+            "emulator-5554          offline transport_id:1\n",
+            "emulator-5554          device product:sdk model:sdk device:generic transport_id:1\nR58M  unauthorized usb:1-1 transport_id:2\n",
+            "R58M  unauthorized usb:1-1 transport_id:2\n",
+        ];
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port() as u32;
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut command = [0; 24];
+            stream.read_exact(&mut command).unwrap();
+            stream.write_all(b"OKAY").unwrap();
+            for snapshot in snapshots {
+                write!(stream, "{:04x}{}", snapshot.len(), snapshot).unwrap();
+            }
+            String::from_utf8(command.to_vec()).unwrap()
+        });
+
+        let client = AdbClient::new(String::from("127.0.0.1"), port, time::Duration::new(10, 0));
+        let events: Vec<_> = client
+            .track_devices()
+            .unwrap()
+            .take(4)
+            .map(|event| event.unwrap())
+            .map(|event| (event.kind, event.present, event.serial, event.status))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (
+                    DeviceEventKind::Connected,
+                    true,
+                    String::from("emulator-5554"),
+                    String::from("offline")
+                ),
+                (
+                    DeviceEventKind::Connected,
+                    true,
+                    String::from("R58M"),
+                    String::from("unauthorized")
+                ),
+                (
+                    DeviceEventKind::StateChanged,
+                    true,
+                    String::from("emulator-5554"),
+                    String::from("device")
+                ),
+                (
+                    DeviceEventKind::Disconnected,
+                    false,
+                    String::from("emulator-5554"),
+                    String::from("absent")
+                ),
+            ]
+        );
+        assert_eq!(server.join().unwrap(), "0014host:track-devices-l");
+    }
+}
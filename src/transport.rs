@@ -0,0 +1,369 @@
+use std::collections::VecDeque;
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time;
+
+use crate::error::AdbError;
+
+pub const DEFAULT_HOST: &str = "localhost";
+pub const DEFAULT_PORT: u32 = 5037;
+
+/// The byte stream an `AdbConnection` talks over.
+pub trait AdbTransport: Read + Write + Send + Debug {
+    /// `None` blocks forever.
+    fn set_read_timeout(&self, time_out: Option<time::Duration>) -> io::Result<()>;
+
+    fn set_write_timeout(&self, time_out: Option<time::Duration>) -> io::Result<()>;
+
+    /// Close both directions, pending and later reads return EOF.
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl AdbTransport for TcpStream {
+    fn set_read_timeout(&self, time_out: Option<time::Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, time_out)
+    }
+
+    fn set_write_timeout(&self, time_out: Option<time::Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, time_out)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+impl AdbTransport for UnixStream {
+    fn set_read_timeout(&self, time_out: Option<time::Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, time_out)
+    }
+
+    fn set_write_timeout(&self, time_out: Option<time::Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, time_out)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+/// Where the adb server listens, displayed in the `ADB_SERVER_SOCKET` syntax.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerAddress {
+    Tcp { host: String, port: u32 },
+    Unix(PathBuf),
+}
+
+impl ServerAddress {
+    /// Resolve the server like the adb tool does: `ADB_SERVER_SOCKET` wins, otherwise
+    /// `ANDROID_ADB_SERVER_ADDRESS` and `ANDROID_ADB_SERVER_PORT` override localhost:5037.
+    pub fn from_env() -> Result<ServerAddress, AdbError> {
+        ServerAddress::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<ServerAddress, AdbError> {
+        // set but empty counts as unset
+        let var = |name: &str| var(name).filter(|value| !value.is_empty());
+        if let Some(socket) = var("ADB_SERVER_SOCKET") {
+            return socket.parse();
+        }
+        let port = match var("ANDROID_ADB_SERVER_PORT") {
+            Some(port) => parse_port(&port).ok_or(AdbError::InvalidServerAddress {
+                address: format!("ANDROID_ADB_SERVER_PORT={}", port),
+            })?,
+            None => DEFAULT_PORT,
+        };
+        Ok(ServerAddress::Tcp {
+            host: var("ANDROID_ADB_SERVER_ADDRESS").unwrap_or_else(|| DEFAULT_HOST.to_string()),
+            port,
+        })
+    }
+
+    pub fn connect(&self) -> io::Result<Box<dyn AdbTransport>> {
+        match self {
+            ServerAddress::Tcp { host, port } => {
+                Ok(Box::new(TcpStream::connect(format!("{}:{}", host, port))?))
+            }
+            ServerAddress::Unix(path) => Ok(Box::new(UnixStream::connect(path)?)),
+        }
+    }
+}
+
+impl Display for ServerAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerAddress::Tcp { host, port } => write!(f, "tcp:{}:{}", host, port),
+            ServerAddress::Unix(path) => write!(f, "localfilesystem:{}", path.display()),
+        }
+    }
+}
+
+fn parse_port(port: &str) -> Option<u32> {
+    match port.parse::<u16>() {
+        Ok(port) if port > 0 => Some(port as u32),
+        _ => None,
+    }
+}
+
+/// Parse "tcp:<port>", "tcp:<host>:<port>" or "localfilesystem:<path>".
+impl FromStr for ServerAddress {
+    type Err = AdbError;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        let invalid = || AdbError::InvalidServerAddress {
+            address: address.to_string(),
+        };
+        if let Some(path) = address.strip_prefix("localfilesystem:") {
+            if path.is_empty() {
+                return Err(invalid());
+            }
+            return Ok(ServerAddress::Unix(PathBuf::from(path)));
+        }
+        let rest = address.strip_prefix("tcp:").ok_or_else(invalid)?;
+        let (host, port) = match rest.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() => (host.to_string(), port),
+            Some(_) => return Err(invalid()),
+            None => (DEFAULT_HOST.to_string(), rest),
+        };
+        Ok(ServerAddress::Tcp {
+            host,
+            port: parse_port(port).ok_or_else(invalid)?,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+    shutdown: bool,
+}
+
+/// A transport without a socket for unit tests: reads are served from the scripted input and
+/// end with EOF once it is drained, writes are recorded. Clones share the same buffers, so keep
+/// one to inspect what was written after handing the other to `AdbConnection::new`.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryTransport {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+impl MemoryTransport {
+    pub fn new(input: &[u8]) -> MemoryTransport {
+        let transport = MemoryTransport::default();
+        transport.push_input(input);
+        transport
+    }
+
+    /// Append to what the next reads return.
+    pub fn push_input(&self, data: &[u8]) {
+        self.lock().input.extend(data);
+    }
+
+    /// Everything written so far.
+    pub fn written(&self) -> Vec<u8> {
+        self.lock().output.clone()
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.lock().shutdown
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemoryState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.lock();
+        if state.shutdown {
+            return Ok(0);
+        }
+        let n = buf.len().min(state.input.len());
+        for (dst, src) in buf.iter_mut().zip(state.input.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.lock();
+        if state.shutdown {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AdbTransport for MemoryTransport {
+    // reads never block, there is nothing to time out
+    fn set_read_timeout(&self, _time_out: Option<time::Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, _time_out: Option<time::Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.lock().shutdown = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MemoryTransport, ServerAddress};
+    use crate::client::{AdbClient, AdbConnection};
+    use crate::error::AdbError;
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use std::time;
+
+    #[test]
+    fn test_memory_transport() {
+        let transport = MemoryTransport::new(b"OKAY0004002aFAIL0006closed");
+        let mut conn = AdbConnection::new(Box::new(transport.clone()));
+        conn.send_command("host:version").unwrap();
+        conn.check_oky().unwrap();
+        assert_eq!(conn.read_string_block().unwrap(), "002a");
+        assert!(matches!(conn.check_oky(), Err(AdbError::ClosedByPeer)));
+        assert_eq!(transport.written(), b"000Chost:version");
+        // the input is drained
+        assert!(matches!(conn.read_bytes(1), Err(AdbError::ClosedByPeer)));
+
+        conn.close().unwrap();
+        assert!(transport.is_shutdown());
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        let path = std::env::temp_dir().join(format!("adbutils-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut command = [0; 16];
+            stream.read_exact(&mut command).unwrap();
+            stream.write_all(b"OKAY00040029").unwrap();
+            command
+        });
+
+        let adb = AdbClient::new(
+            String::from("127.0.0.1"),
+            5037,
+            time::Duration::from_secs(2),
+        )
+        .with_unix_socket(&path);
+        assert_eq!(adb.address(), ServerAddress::Unix(path.clone()));
+        assert_eq!(adb.server_version().unwrap(), 41);
+        assert_eq!(&server.join().unwrap(), b"000Chost:version");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_address_from_vars() {
+        let from = |vars: &[(&str, &str)]| {
+            let vars = vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<HashMap<String, String>>();
+            ServerAddress::from_vars(|name| vars.get(name).cloned())
+        };
+        let tcp = |host: &str, port: u32| ServerAddress::Tcp {
+            host: host.to_string(),
+            port,
+        };
+        assert_eq!(from(&[]).unwrap(), tcp("localhost", 5037));
+        assert_eq!(
+            from(&[
+                ("ANDROID_ADB_SERVER_ADDRESS", "host.docker.internal"),
+                ("ANDROID_ADB_SERVER_PORT", "5038"),
+            ])
+            .unwrap(),
+            tcp("host.docker.internal", 5038)
+        );
+        assert_eq!(
+            from(&[
+                ("ANDROID_ADB_SERVER_PORT", ""),
+                ("ANDROID_ADB_SERVER_ADDRESS", "")
+            ])
+            .unwrap(),
+            tcp("localhost", 5037)
+        );
+        // the socket wins over the other two
+        assert_eq!(
+            from(&[
+                ("ADB_SERVER_SOCKET", "tcp:10.0.2.2:5037"),
+                ("ANDROID_ADB_SERVER_PORT", "5038"),
+            ])
+            .unwrap(),
+            tcp("10.0.2.2", 5037)
+        );
+        assert_eq!(
+            from(&[("ADB_SERVER_SOCKET", "localfilesystem:/run/adb.sock")]).unwrap(),
+            ServerAddress::Unix(PathBuf::from("/run/adb.sock"))
+        );
+        assert!(matches!(
+            from(&[("ANDROID_ADB_SERVER_PORT", "adb")]),
+            Err(AdbError::InvalidServerAddress { .. })
+        ));
+    }
+
+    #[test]
+    fn test_address_parse() {
+        let tcp = |host: &str, port: u32| ServerAddress::Tcp {
+            host: host.to_string(),
+            port,
+        };
+        assert_eq!(
+            "tcp:5038".parse::<ServerAddress>().unwrap(),
+            tcp("localhost", 5038)
+        );
+        assert_eq!(
+            "tcp:[::1]:5037".parse::<ServerAddress>().unwrap(),
+            tcp("[::1]", 5037)
+        );
+        for invalid in [
+            "5037",
+            "tcp:",
+            "tcp::5037",
+            "tcp:localhost:0",
+            "tcp:localhost:70000",
+            "localfilesystem:",
+            "localabstract:adb",
+        ] {
+            match invalid.parse::<ServerAddress>() {
+                Err(AdbError::InvalidServerAddress { address }) => assert_eq!(address, invalid),
+                other => panic!("{} parsed as {:?}", invalid, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_address_display() {
+        let tcp = ServerAddress::Tcp {
+            host: String::from("127.0.0.1"),
+            port: 5037,
+        };
+        assert_eq!(tcp.to_string(), "tcp:127.0.0.1:5037");
+        let unix = ServerAddress::Unix(PathBuf::from("/tmp/adb.sock"));
+        assert_eq!(unix.to_string(), "localfilesystem:/tmp/adb.sock");
+    }
+}